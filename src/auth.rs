@@ -0,0 +1,402 @@
+//! JWT and API key authentication, the tokens come from `Authorization: Bearer <token>`
+//! and the API keys from `X-Api-Key: <key>` or `Authorization: ApiKey <key>`.
+//! The key is read from the environment, or from the file named by the same variable with a `_FILE` suffix:
+//!
+//! - `JWT_ALGORITHM`: `HS256` (the default) or `RS256`
+//! - `JWT_SECRET`: the shared secret of HS256
+//! - `JWT_PUBLIC_KEY`: the PEM public key of RS256
+//!
+//! There is no default key, the servers refuse to start without one. `.env.example` lists the variables.
+
+use super::models::{Db, Scope, db_find_api_key};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::{reject, Filter, Rejection};
+
+/// The role allowed to do everything.
+pub const ADMIN_ROLE: &str = "admin";
+
+pub type Auth = Arc<AuthConfig>;
+
+/// The claims we expect in our tokens.
+/// API keys are turned into claims too, on behalf of the admin who created them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Claims {
+    pub sub: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub exp: usize,
+    /// What the caller may do, `None` allows everything.
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+}
+
+impl Claims {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.as_ref().map_or(true, |scopes| scopes.iter().any(|s| s == scope))
+    }
+
+    pub fn require_scope(&self, scope: &'static str) -> Result<(), AuthError> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(AuthError::MissingScope(scope))
+        }
+    }
+
+    /// The todos the caller can see and change: all of them for an admin, their own otherwise.
+    pub fn scope(&self) -> Scope {
+        if self.has_role(ADMIN_ROLE) {
+            Scope::Everything
+        } else {
+            Scope::Owner(self.sub.clone())
+        }
+    }
+}
+
+/// How to check the tokens, and where to find the API keys.
+pub struct AuthConfig {
+    key: DecodingKey<'static>,
+    validation: Validation,
+    db: Db,
+}
+
+impl AuthConfig {
+    pub fn from_env(db: Db) -> Result<Auth, String> {
+        let algorithm = env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string());
+        let (key, algorithm) = match algorithm.as_str() {
+            "HS256" => return Ok(Self::hs256(&setting("JWT_SECRET")?, db)),
+            "RS256" => {
+                let pem = setting("JWT_PUBLIC_KEY")?;
+                let key = DecodingKey::from_rsa_pem(pem.as_bytes())
+                    .map_err(|e| format!("invalid JWT_PUBLIC_KEY: {}", e))?;
+                (key.into_static(), Algorithm::RS256)
+            }
+            other => return Err(format!("unsupported JWT_ALGORITHM {}, expected HS256 or RS256", other)),
+        };
+        Ok(Arc::new(AuthConfig { key, validation: Validation::new(algorithm), db }))
+    }
+
+    /// Check the tokens signed with the shared `secret`.
+    pub fn hs256(secret: &str, db: Db) -> Auth {
+        let key = DecodingKey::from_secret(secret.as_bytes()).into_static();
+        Arc::new(AuthConfig { key, validation: Validation::new(Algorithm::HS256), db })
+    }
+
+    /// Find who is calling from the `Authorization` and `X-Api-Key` headers.
+    pub async fn identify(&self, authorization: Option<String>, api_key: Option<String>) -> Result<Option<Claims>, AuthError> {
+        if let Some(api_key) = api_key {
+            return self.verify_api_key(&api_key).await.map(Some);
+        }
+        match authorization {
+            Some(header) => match header.strip_prefix("ApiKey ") {
+                Some(api_key) => self.verify_api_key(api_key).await.map(Some),
+                None => self.verify(&header).map(Some),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Check the value of an `Authorization: Bearer` header.
+    fn verify(&self, header: &str) -> Result<Claims, AuthError> {
+        let token = header.strip_prefix("Bearer ")
+            .ok_or(AuthError::MissingToken)?;
+        decode::<Claims>(token.trim(), &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|e| AuthError::InvalidToken(e.to_string()))
+    }
+
+    /// Check an API key against its salted hash.
+    async fn verify_api_key(&self, key: &str) -> Result<Claims, AuthError> {
+        let invalid = || AuthError::InvalidToken("unknown API key".to_string());
+        let mut parts = key.trim().splitn(2, '.');
+        let (prefix, secret) = match (parts.next(), parts.next()) {
+            (Some(prefix), Some(secret)) => (prefix, secret),
+            _ => return Err(invalid()),
+        };
+        let api_key = db_find_api_key(prefix, &self.db).await
+            .map_err(|e| AuthError::Unavailable(e.to_string()))?
+            .ok_or_else(invalid)?;
+        if !constant_time_eq(hash_secret(&api_key.salt, secret).as_bytes(), api_key.hash.as_bytes()) {
+            return Err(invalid());
+        }
+        if api_key.revoked_at.is_some() {
+            return Err(AuthError::InvalidToken("revoked API key".to_string()));
+        }
+        Ok(Claims {
+            sub: api_key.owner_id,
+            roles: Vec::new(),
+            exp: 0,
+            scopes: Some(api_key.scopes.split_whitespace().map(String::from).collect()),
+        })
+    }
+}
+
+/// A new API key, `key` is given to the client once, the rest is stored.
+pub struct GeneratedApiKey {
+    pub key: String,
+    pub prefix: String,
+    pub salt: String,
+    pub hash: String,
+}
+
+/// Draw a random API key of the form `<prefix>.<secret>`, the prefix is used to find it back.
+pub fn generate_api_key() -> GeneratedApiKey {
+    let prefix = hex::encode(rand::random::<[u8; 8]>());
+    let secret = hex::encode(rand::random::<[u8; 32]>());
+    let salt = hex::encode(rand::random::<[u8; 16]>());
+    let hash = hash_secret(&salt, &secret);
+    GeneratedApiKey { key: format!("{}.{}", prefix, secret), prefix, salt, hash }
+}
+
+fn hash_secret(salt: &str, secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(salt.as_bytes());
+    hasher.input(secret.as_bytes());
+    format!("{:x}", hasher.result())
+}
+
+/// Compare without leaking through timing how many bytes match.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// `name` from the environment, or the content of the file at `name_FILE`.
+// There is no default key, a well known one would let anyone sign admin tokens
+fn setting(name: &str) -> Result<String, String> {
+    let value = match env::var(name) {
+        Ok(value) => value,
+        Err(_) => {
+            let path = env::var(format!("{}_FILE", name))
+                .map_err(|_| format!("{} or {}_FILE must be set", name, name))?;
+            fs::read_to_string(&path)
+                .map_err(|e| format!("cannot read {}: {}", path, e))?
+        }
+    };
+    if value.trim().is_empty() {
+        return Err(format!("{} must not be empty", name));
+    }
+    Ok(value)
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// No bearer token in the request.
+    MissingToken,
+    /// The token is expired, or was not signed by our key.
+    InvalidToken(String),
+    /// The token doesn't carry the role needed by the route.
+    MissingRole(&'static str),
+    /// The token or API key doesn't carry the scope needed by the route.
+    MissingScope(&'static str),
+    /// The API keys could not be read.
+    Unavailable(String),
+}
+
+impl AuthError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthError::MissingToken | AuthError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            AuthError::MissingRole(_) | AuthError::MissingScope(_) => StatusCode::FORBIDDEN,
+            AuthError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "UNAUTHORIZED: missing bearer token"),
+            AuthError::InvalidToken(reason) => write!(f, "UNAUTHORIZED: {}", reason),
+            AuthError::MissingRole(role) => write!(f, "FORBIDDEN: the {} role is needed", role),
+            AuthError::MissingScope(scope) => write!(f, "FORBIDDEN: the {} scope is needed", scope),
+            AuthError::Unavailable(reason) => write!(f, "STORAGE_UNAVAILABLE: {}", reason),
+        }
+    }
+}
+
+impl reject::Reject for AuthError {}
+
+/// The claims of the caller if there is a token or an API key, a bad one is still rejected.
+pub fn optional(auth: Auth) -> impl Filter<Extract = (Option<Claims>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and_then(move |authorization: Option<String>, api_key: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                auth.identify(authorization, api_key).await.map_err(reject::custom)
+            }
+        })
+}
+
+/// The claims of the caller, who must send a valid token.
+pub fn authenticated(auth: Auth) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    optional(auth).and_then(|claims: Option<Claims>| async move {
+        claims.ok_or_else(|| reject::custom(AuthError::MissingToken))
+    })
+}
+
+/// The claims of the caller, who must have `scope`.
+pub fn with_scope(auth: Auth, scope: &'static str) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    authenticated(auth).and_then(move |claims: Claims| async move {
+        claims.require_scope(scope).map(|_| claims).map_err(reject::custom)
+    })
+}
+
+/// Where a browser puts its bearer token on the routes it opens with `EventSource`,
+/// which can't send an `Authorization` header: `?access_token=<token>` or an `access_token` cookie.
+pub const TOKEN_PARAMETER: &str = "access_token";
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    access_token: Option<String>,
+}
+
+/// The claims of the caller, who must have `scope`. Without an `Authorization` or `X-Api-Key` header
+/// the bearer token is also looked for in the query string, then in a cookie.
+pub fn with_scope_from_browser(auth: Auth, scope: &'static str) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(warp::query::<TokenQuery>())
+        .and(warp::cookie::optional(TOKEN_PARAMETER))
+        .and_then(move |authorization: Option<String>, api_key: Option<String>, query: TokenQuery, cookie: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                let authorization = authorization.or_else(|| {
+                    query.access_token.or(cookie).map(|token| format!("Bearer {}", token))
+                });
+                let claims = auth.identify(authorization, api_key).await
+                    .map_err(reject::custom)?
+                    .ok_or_else(|| reject::custom(AuthError::MissingToken))?;
+                claims.require_scope(scope).map(|_| claims).map_err(reject::custom)
+            }
+        })
+}
+
+/// The claims of the caller, who must have `role`.
+pub fn with_role(auth: Auth, role: &'static str) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    authenticated(auth).and_then(move |claims: Claims| async move {
+        if claims.has_role(role) {
+            Ok(claims)
+        } else {
+            Err(reject::custom(AuthError::MissingRole(role)))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::models::{db_create_api_key, db_revoke_api_key, CreateApiKey, MemoryTodoRepository};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use warp::test::request;
+
+    const SECRET: &str = "test-secret";
+
+    fn token(sub: &str) -> String {
+        let claims = Claims { sub: sub.to_string(), roles: Vec::new(), exp: 4_000_000_000, scopes: None };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+    }
+
+    fn auth() -> Auth {
+        AuthConfig::hs256(SECRET, Arc::new(MemoryTodoRepository::new()))
+    }
+
+    // A key of alice with `scopes`, its id and the key given to the client
+    async fn api_key(db: &Db, scopes: &[&str]) -> (i32, String) {
+        let generated = generate_api_key();
+        let create = CreateApiKey { name: "ci".to_string(), scopes: scopes.iter().map(|scope| scope.to_string()).collect() };
+        let api_key = db_create_api_key(create, generated.prefix, generated.salt, generated.hash, "alice".to_string(), db).await.unwrap();
+        (api_key.id, generated.key)
+    }
+
+    fn status(rejection: Rejection) -> Option<StatusCode> {
+        rejection.find::<AuthError>().map(AuthError::status)
+    }
+
+    #[tokio::test]
+    async fn browser_tokens() {
+        let filter = with_scope_from_browser(auth(), "todos:read");
+        let claims = request().path(&format!("/?access_token={}", token("alice"))).filter(&filter).await.unwrap();
+        assert_eq!(claims.sub, "alice");
+        let claims = request().path("/").header("cookie", format!("access_token={}", token("bob"))).filter(&filter).await.unwrap();
+        assert_eq!(claims.sub, "bob");
+        // The header wins over the query string
+        let claims = request().path(&format!("/?access_token={}", token("alice")))
+            .header("authorization", format!("Bearer {}", token("carol")))
+            .filter(&filter).await.unwrap();
+        assert_eq!(claims.sub, "carol");
+        assert!(request().path("/").filter(&filter).await.is_err());
+        assert!(request().path("/?access_token=forged").filter(&filter).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn api_keys() {
+        let db: Db = Arc::new(MemoryTodoRepository::new());
+        let filter = authenticated(AuthConfig::hs256(SECRET, db.clone()));
+        let (id, key) = api_key(&db, &["todos:read"]).await;
+        let claims = request().path("/").header("x-api-key", &key).filter(&filter).await.unwrap();
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(claims.scopes, Some(vec!["todos:read".to_string()]));
+        let claims = request().path("/").header("authorization", format!("ApiKey {}", key)).filter(&filter).await.unwrap();
+        assert_eq!(claims.sub, "alice");
+
+        // A known prefix with another secret
+        let prefix = key.split('.').next().unwrap();
+        let forged = format!("{}.{}", prefix, "0".repeat(64));
+        let rejection = request().path("/").header("x-api-key", &forged).filter(&filter).await.unwrap_err();
+        assert_eq!(status(rejection), Some(StatusCode::UNAUTHORIZED));
+        let rejection = request().path("/").header("x-api-key", "garbage").filter(&filter).await.unwrap_err();
+        assert_eq!(status(rejection), Some(StatusCode::UNAUTHORIZED));
+
+        db_revoke_api_key(id, "alice", &db).await.unwrap();
+        let rejection = request().path("/").header("x-api-key", &key).filter(&filter).await.unwrap_err();
+        assert_eq!(status(rejection), Some(StatusCode::UNAUTHORIZED));
+        let rejection = request().path("/").header("authorization", format!("ApiKey {}", key)).filter(&filter).await.unwrap_err();
+        assert_eq!(status(rejection), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn api_key_scopes() {
+        let db: Db = Arc::new(MemoryTodoRepository::new());
+        let filter = with_scope(AuthConfig::hs256(SECRET, db.clone()), "todos:write");
+        let (_, read_only) = api_key(&db, &["todos:read"]).await;
+        let rejection = request().path("/").header("x-api-key", &read_only).filter(&filter).await.unwrap_err();
+        assert_eq!(status(rejection), Some(StatusCode::FORBIDDEN));
+        let (_, writer) = api_key(&db, &["todos:read", "todos:write"]).await;
+        assert!(request().path("/").header("x-api-key", &writer).filter(&filter).await.is_ok());
+    }
+
+    // The only test touching the JWT variables, the others build their `Auth` with `hs256`
+    #[test]
+    fn a_key_is_required() {
+        env::remove_var("JWT_ALGORITHM");
+        env::remove_var("JWT_SECRET_FILE");
+        env::remove_var("JWT_SECRET");
+        let db: Db = Arc::new(MemoryTodoRepository::new());
+        assert_eq!(AuthConfig::from_env(db.clone()).err(), Some("JWT_SECRET or JWT_SECRET_FILE must be set".to_string()));
+        env::set_var("JWT_SECRET", " ");
+        assert_eq!(AuthConfig::from_env(db.clone()).err(), Some("JWT_SECRET must not be empty".to_string()));
+        env::set_var("JWT_SECRET", SECRET);
+        assert!(AuthConfig::from_env(db).is_ok());
+        env::remove_var("JWT_SECRET");
+    }
+
+    #[tokio::test]
+    async fn headers_only_elsewhere() {
+        let filter = with_scope(auth(), "todos:read");
+        assert!(request().path(&format!("/?access_token={}", token("alice"))).filter(&filter).await.is_err());
+        let claims = request().path("/").header("authorization", format!("Bearer {}", token("alice"))).filter(&filter).await.unwrap();
+        assert_eq!(claims.sub, "alice");
+    }
+}
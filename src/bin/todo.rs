@@ -1,190 +1,101 @@
 //#![deny(warnings)]
 
-//use warp::Filter;
+use warp::Filter;
+use warp_tutorial::{auth, events, filters, handlers, models};
 
 /// Provides a RESTfull web server managing some Todos
 /// API will be:
 ///
-/// - `GET /todos`: return a JSON list of Todos
-/// - `POST /todos`: create a new Todo
+/// - `GET /todos`: return a JSON page of Todos, with `Link` headers to the next and previous pages
+///   `?offset=&limit=` pages by position, `?after=<cursor>&limit=` pages by key,
+///   `?completed=true&q=milk&sort=-id` filters and sorts the list
+/// - `GET /todos/:id`: return a specific Todo, with an `ETag` honouring `If-None-Match`
+/// - `POST /todos`: create a new Todo, the id is assigned by the server
 /// - `PUT /todos/:id`: update a specific Todo.
-/// - `DELETE /todos/:id`: delete a specific Todo.
+/// - `PATCH /todos/:id`: update some fields of a specific Todo, JSON or JSON Merge Patch body.
+/// - `DELETE /todos/:id`: move a specific Todo to the trash.
+/// - `DELETE /todos?completed=true`: move the completed Todos to the trash, and return them.
+/// - `GET /todos/trash`: the Todos in the trash, they are purged after `TRASH_RETENTION_DAYS` (30 by default).
+/// - `POST /todos/:id/restore`: take a Todo out of the trash.
+/// - `GET /todos/:id/history`: every write of a Todo, who made it, when, and the Todo before and after.
+/// - `POST /todos/bulk`: create, update and delete many Todos in a single transaction,
+///   `all_or_nothing` (the default) or `best_effort`, with the result of every operation.
+/// - `GET /todos/export?format=jsonl|csv|md`: stream every Todo as JSON Lines, CSV or a Markdown task list.
+/// - `POST /todos/import?format=jsonl|csv|md`: create the Todos of such a document, all of them or none
+///   when a line is invalid, the errors give the line numbers.
+/// - `GET /todos/events`: a Server-Sent Events stream of the `created`, `updated` and `deleted` Todos,
+///   a `Last-Event-ID` header resumes after that event while it is still in the replay buffer of this instance,
+///   an id from another instance or from before a restart only gets the new events.
+///   `EventSource` can't send headers, the token can come as `?access_token=<token>` or an `access_token` cookie.
+///
+/// `PUT`, `PATCH` and `DELETE` honour `If-Match` and answer 412 when the Todo has changed.
+/// `PUT` and `PATCH` also accept the `version` of the Todo, and answer 409 when it is stale.
+/// Every route needs a valid JWT, callers only see their own Todos unless they have the admin role.
+/// Automation can use an API key instead, sent as `X-Api-Key: <key>` or `Authorization: ApiKey <key>`:
+///
+/// - `GET /api-keys`: list the API keys created by the caller, admin only.
+/// - `POST /api-keys`: create an API key with some scopes, the key is only shown in this response.
+/// - `DELETE /api-keys/:id`: revoke an API key created by the caller.
+/// - `GET /audit?actor=&todo_id=&since=&until=`: the writes of every Todo newest first, admin only,
+///   `before=<id>` with the id of the last entry seen gives the next page.
 
 #[tokio::main]
 async fn main() {
+    let storage = match models::storage() {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("cannot open the todo storage: {}", e);
+            return;
+        }
+    };
+
     // Db intialization, i keep the workd blank_db, but this database is not blank anymore
-    let db = models::blank_db().await;
+    let db = match models::blank_db(&storage).await {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("cannot open the todo storage: {}", e);
+            return;
+        }
+    };
+
+    // Every write goes through the bus, for the event stream. Postgres notifies the writes
+    // of every instance, this one included, the in-memory storage publishes its own.
+    let events = events::EventBus::new();
+    let db = match storage {
+        #[cfg(feature = "postgres")]
+        models::Storage::Postgres(url) => {
+            tokio::spawn(events::listen(url, db.clone(), events.clone()));
+            db
+        }
+        models::Storage::Memory => events::publishing(db, events.clone()),
+    };
+
+    let retention = match models::trash_retention() {
+        Ok(retention) => retention,
+        Err(e) => {
+            eprintln!("cannot configure the trash: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(models::purge_trash(retention, db.clone()));
+
+    let auth = match auth::AuthConfig::from_env(db.clone()) {
+        Ok(auth) => auth,
+        Err(e) => {
+            eprintln!("cannot configure the authentication: {}", e);
+            return;
+        }
+    };
 
     // Define api filter
-    let rest_api = filters::rest_todos(db);
+    let rest_api = filters::rest_todos(db.clone(), auth.clone())
+        .or(filters::todos_events(events, auth.clone()))
+        .or(filters::rest_api_keys(db.clone(), auth.clone()))
+        .or(filters::audit_list(db, auth));
 
-    // Define root of all our routes
-    let routes = rest_api;
+    // Define root of all our routes, every rejection is turned into a JSON error
+    let routes = rest_api.recover(handlers::handle_rejection);
 
     // Start server
     warp::serve(routes).run(([192, 168, 0, 10], 3030)).await;
 }
-
-mod filters {
-    use super::handlers;
-    use super::models::{Db, ListOptions, Todo};
-    use warp::Filter;
-
-    /// The 4 TODOs filters combined.
-    pub fn rest_todos(db: Db,) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        todos_list(db.clone())
-            .or(todos_create(db.clone()))
-            .or(todos_update(db.clone()))
-            .or(todos_delete(db))
-    }
-
-    /// GET /todos?offset=3&limit=5
-    pub fn todos_list(db: Db) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("todos")
-            .and(warp::get())
-            .and(warp::query::<ListOptions>())
-            .and(with_db(db))
-            .and_then(handlers::list_todos)
-    }
-
-    /// POST /todos with JSON body
-    pub fn todos_create(db: Db) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("todos")
-            .and(warp::post())
-            .and(json_body())
-            .and(with_db(db))
-            .and_then(handlers::create_todos)
-    }
-
-    /// PUT /todos/:id with JSON BODY
-    pub fn todos_update(db: Db) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("todos" / i32)
-            .and(warp::put())
-            .and(json_body())
-            .and(with_db(db))
-            .and_then(handlers::update_todo)
-    }
-
-    /// DELETE /todos/:id
-    pub fn todos_delete(db: Db) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        // we'll make one of our endpoints admin-only to show how authentification filters are used
-        let admin_only = warp::header::exact("authorization", "Bearer admin");
-
-        warp::path!("todos" / i32)
-            .and(admin_only)
-            .and(warp::delete())
-            .and(with_db(db))
-            .and_then(handlers::delete_todo)
-    }
-
-    /// Make the db accessible within filter
-    fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = std::convert::Infallible> + Clone {
-        warp::any().map(move || db.clone())
-    }
-
-    fn json_body() -> impl Filter<Extract= (Todo,), Error = warp::Rejection> + Clone {
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
-    }
-}
-
-
-/// These are ou API handlers, the ends of each filter chain.
-/// Notice how thanks to using `Filter::and`, we can define a function
-/// with the exact arguments we'd expect from each filter in the chain.
-/// No tuples are needed, it's auto flattened for the functions.
-mod handlers {
-    use super::models::{Db, ListOptions, Todo, db_list_todos, db_create_todos, db_update_todo, db_delete_todo};
-    use std::convert::Infallible;
-    use warp::http::StatusCode;
-
-    pub async fn list_todos(opts: ListOptions, db: Db) -> Result<impl warp::Reply, Infallible> {
-        let todos_list = db_list_todos(opts.offset.unwrap_or(0), opts.limit.unwrap_or(std::i32::MAX), &db).await;
-        Ok(warp::reply::json(&todos_list))
-    }
-
-    pub async fn create_todos(create: Todo, db: Db) -> Result<impl warp::Reply, Infallible> {
-        let row = db_create_todos(create.id, create.text, create.completed, &db).await;
-        if row != 0 {
-            Ok(StatusCode::CREATED)
-        } else {
-            Ok(StatusCode::BAD_REQUEST)
-        }
-    }
-
-    pub async fn update_todo(id: i32, update: Todo, db: Db) -> Result<impl warp::Reply, Infallible> {
-        let rows = db_update_todo(id, update.text, update.completed, &db).await;
-        if rows != 0 {
-            Ok(StatusCode::OK)
-        } else {
-            Ok(StatusCode::NOT_FOUND)
-        }
-    }
-
-    pub async fn delete_todo(id: i32, db: Db) -> Result<impl warp::Reply, Infallible> {
-        let rows = db_delete_todo(id, &db).await;
-        if rows != 0 {
-            Ok(StatusCode::NO_CONTENT)
-        } else {
-            Ok(StatusCode::NOT_FOUND)
-        }
-    }
-}
-
-mod models {
-    use serde::{Deserialize, Serialize};
-    use sqlx::PgPool;
-    use std::env;
-
-    // So we don't have to tackle how different database work, we'll just use
-    // a simple in-memory DB, a vector synchronized by Mutex
-    //1 pub type Db = Arc<Mutex<PgPool>>;
-    pub type Db = PgPool;
-
-    #[derive(Debug, Deserialize, Serialize, Clone)] //, sqlx::FromRow
-    pub struct Todo {
-        pub id: i32,
-        pub text: String,
-        pub completed: bool
-    }
-
-    // The query parameters for list_todos.
-    #[derive(Debug, Deserialize)]
-    pub struct ListOptions {
-        pub offset: Option<i32>,
-        pub limit: Option<i32>,
-    }
-
-    pub async fn blank_db() -> Db {
-        dotenv::dotenv().ok();
-        //Arc::new(Mutex::new(Vec::new()))
-        //let pool = SqlitePool::new("sqlite:///Users/akersof/CLionProjects/warp-tutorial/todos.db").await.unwrap();
-        let pool = PgPool::builder().max_size(10).build(&env::var("DATABASE_URL").unwrap()).await.unwrap();
-        //1 Arc::new(Mutex::new(pool))
-        pool
-    }
-
-    // Here perform various known request, they will be called by the corresponding handler
-    pub async fn db_list_todos(offset: i32, limit: i32, db: &Db) -> Vec<Todo> {
-        let todos_list = sqlx::query_as!(Todo, "SELECT * FROM todos WHERE id BETWEEN $1 and $2", offset, limit)
-            .fetch_all(db).await.unwrap();
-        todos_list
-    }
-
-    pub async fn db_create_todos(id: i32, text: String, completed: bool, db: &Db) -> u64 {
-        let rows = sqlx::query!("INSERT INTO todos VALUES($1, $2, $3)", id, text, completed)
-            .execute(db).await.unwrap();
-        rows
-    }
-
-    pub async fn db_update_todo(id: i32, text: String, completed: bool, db: &Db) -> u64 {
-        let rows = sqlx::query!("UPDATE todos SET text = $1, completed = $2 where id = $3", text, completed, id)
-            .execute(db).await.unwrap();
-        rows
-    }
-
-    pub async fn db_delete_todo(id: i32, db: &Db) -> u64 {
-        let rows = sqlx::query!("DELETE FROM todos WHERE id = $1", id)
-            .execute(db).await.unwrap();
-        rows
-    }
-}
\ No newline at end of file
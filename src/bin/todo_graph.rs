@@ -1,259 +1,1154 @@
 //#![deny(warnings)]
 
 use warp::Filter;
+use warp_tutorial::{auth, events, filters, gql, handlers, models};
 
 
 /// Provides a RESTfull web server managing some Todos
 /// API will be:
 ///
-/// - `GET /todos`: return a JSON list of Todos
-/// - `POST /todos`: create a new Todo
+/// - `GET /todos`: return a JSON page of Todos, with `Link` headers to the next and previous pages
+///   `?offset=&limit=` pages by position, `?after=<cursor>&limit=` pages by key,
+///   `?completed=true&q=milk&sort=-id` filters and sorts the list
+/// - `GET /todos/:id`: return a specific Todo, with an `ETag` honouring `If-None-Match`
+/// - `POST /todos`: create a new Todo, the id is assigned by the server
 /// - `PUT /todos/:id`: update a specific Todo.
-/// - `DELETE /todos/:id`: delete a specific Todo.
+/// - `PATCH /todos/:id`: update some fields of a specific Todo, JSON or JSON Merge Patch body.
+/// - `DELETE /todos/:id`: move a specific Todo to the trash.
+/// - `DELETE /todos?completed=true`: move the completed Todos to the trash, and return them.
+/// - `GET /todos/trash`: the Todos in the trash, they are purged after `TRASH_RETENTION_DAYS` (30 by default).
+/// - `POST /todos/:id/restore`: take a Todo out of the trash.
+/// - `GET /todos/:id/history`: every write of a Todo, who made it, when, and the Todo before and after.
+/// - `POST /todos/bulk`: create, update and delete many Todos in a single transaction,
+///   `all_or_nothing` (the default) or `best_effort`, with the result of every operation.
+/// - `GET /todos/export?format=jsonl|csv|md`: stream every Todo as JSON Lines, CSV or a Markdown task list.
+/// - `POST /todos/import?format=jsonl|csv|md`: create the Todos of such a document, all of them or none
+///   when a line is invalid, the errors give the line numbers.
+/// - `GET /todos/events`: a Server-Sent Events stream of the `created`, `updated` and `deleted` Todos,
+///   a `Last-Event-ID` header resumes after that event while it is still in the replay buffer.
+///   `EventSource` can't send headers, the token can come as `?access_token=<token>` or an `access_token` cookie.
+///
+/// `PUT`, `PATCH` and `DELETE` honour `If-Match` and answer 412 when the Todo has changed.
+/// `PUT` and `PATCH` also accept the `version` of the Todo, and answer 409 when it is stale.
+/// Every route needs a valid JWT, callers only see their own Todos unless they have the admin role.
+/// Automation can use an API key instead, sent as `X-Api-Key: <key>` or `Authorization: ApiKey <key>`:
+///
+/// - `GET /api-keys`: list the API keys created by the caller, admin only.
+/// - `POST /api-keys`: create an API key with some scopes, the key is only shown in this response.
+/// - `DELETE /api-keys/:id`: revoke an API key created by the caller.
+/// - `GET /audit?actor=&todo_id=&since=&until=`: the writes of every Todo newest first, admin only,
+///   `before=<id>` with the id of the last entry seen gives the next page.
+///
+/// The same Todos are served by GraphQL on `/graphql`, queried by `todosList` and `todo(id:)` and changed by
+/// the `createTodo`, `updateTodo`, `toggleTodo`, `deleteTodo` and `clearCompleted` mutations.
+/// `todos(first, after, last, before, filter)` pages them as a Relay connection. `Todo` implements the
+/// `Node` interface: its `id` is a global id which `node(id:)` refetches, the REST id is `databaseId`.
+/// This changed `Todo.id` from `Int!` to `ID!`: clients which read it as the REST id must select
+/// `databaseId` instead. The `id` argument of `todo`, `updateTodo`, `toggleTodo` and `deleteTodo` is an
+/// `ID` which takes the global id or the `databaseId`, variables passed to it are declared `ID`.
+/// Its `history(last:)` field lists its last writes, as `GET /todos/:id/history` does, 20 by default and 100 at most.
+/// A JSON array of operations is run as a batch, one operation after the other.
+/// The todos the `todo` and `node` fields of an operation ask for are loaded together.
+/// Operations over the depth, field count or cost limits are refused before they run, subscriptions included,
+/// see `GRAPHQL_MAX_DEPTH`, `GRAPHQL_MAX_FIELDS` and `GRAPHQL_MAX_COST`. The operations of a batch share one budget.
+/// A POST body is JSON, or the document alone with `Content-Type: application/graphql`.
+/// `/graphql` and `/subscriptions` speak the automatic persisted queries protocol, and with `GRAPHQL_ALLOWLIST`
+/// only run the documents of that manifest.
+///
+/// - `GET /graphiql` and `GET /playground`: the GraphiQL and GraphQL Playground IDEs, pointed at `/graphql`.
+/// - `GET /graphql/schema.graphql`: the schema in the GraphQL schema language, for codegen.
+///
+/// `todo_graph --print-schema` writes the same schema to stdout and exits.
+/// The `todoCreated`, `todoUpdated` and `todoDeleted` subscriptions are served with the graphql-ws
+/// protocol on the `/subscriptions` WebSocket, they see the writes of both APIs. Browsers can't set the
+/// headers of a WebSocket, the token can also be sent in the `connection_init` payload, as `authorization`
+/// (`Bearer <token>` or `ApiKey <key>`), `x-api-key` or a bare `access_token`.
+/// With Postgres they also see the writes of the other instances, through the `todos_changed`
+/// trigger of `todo.sql`.
 
-use juniper::{FieldResult, EmptySubscription};
 use std::sync::Arc;
 #[tokio::main]
 async fn main() {
+    if std::env::args().skip(1).any(|arg| arg == "--print-schema") {
+        print!("{}", gql::schema().as_schema_language());
+        return;
+    }
+
+    let storage = match models::storage() {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("cannot open the todo storage: {}", e);
+            return;
+        }
+    };
+
     // Db intialization, i keep the workd blank_db, but this database is not blank anymore
-    let db = models::blank_db().await;
+    let db = match models::blank_db(&storage).await {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("cannot open the todo storage: {}", e);
+            return;
+        }
+    };
+
+    // Every write goes through the bus, for the subscriptions and the event stream. Postgres notifies the writes
+    // of every instance, this one included, the in-memory storage publishes its own.
+    let events = events::EventBus::new();
+    let db = match storage {
+        #[cfg(feature = "postgres")]
+        models::Storage::Postgres(url) => {
+            tokio::spawn(events::listen(url, db.clone(), events.clone()));
+            db
+        }
+        models::Storage::Memory => events::publishing(db, events.clone()),
+    };
+
+    let retention = match models::trash_retention() {
+        Ok(retention) => retention,
+        Err(e) => {
+            eprintln!("cannot configure the trash: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(models::purge_trash(retention, db.clone()));
+
+    let auth = match auth::AuthConfig::from_env(db.clone()) {
+        Ok(auth) => auth,
+        Err(e) => {
+            eprintln!("cannot configure the authentication: {}", e);
+            return;
+        }
+    };
 
 
-    let context = warp::any().and(filters::with_db(db.clone())).map(|db: models::Db|
-        gql::Context{pool: db}
-    );
+    let context = warp::any()
+        .and(filters::with_db(db.clone()))
+        .and(auth::optional(auth.clone()))
+        .and(events::with_events(events.clone()))
+        .map(|db: models::Db, claims: Option<auth::Claims>, events: events::Events|
+            gql::Context::new(db, claims, events)
+        );
 
-    let graphql_filter = juniper_warp::make_graphql_filter(gql::schema(), context.boxed());
+    let limits = match limits::Limits::from_env() {
+        Ok(limits) => Arc::new(limits),
+        Err(e) => {
+            eprintln!("cannot configure the GraphQL limits: {}", e);
+            return;
+        }
+    };
+
+    let persisted = match persisted::PersistedQueries::from_env() {
+        Ok(persisted) => Arc::new(persisted),
+        Err(e) => {
+            eprintln!("cannot load the persisted queries: {}", e);
+            return;
+        }
+    };
+
+    let schema = Arc::new(gql::schema());
+    let graphql_filter = graphql::graphql_filter(schema.clone(), context.boxed(), limits.clone(), persisted.clone());
+
+    // Generated once, the schema doesn't change while the server runs
+    let sdl = gql::schema().as_schema_language();
+    let schema_sdl = warp::path!("graphql" / "schema.graphql")
+        .and(warp::get())
+        .map(move || warp::reply::with_header(sdl.clone(), "content-type", "text/plain; charset=utf-8"));
+
+    let graphiql = warp::path("graphiql")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(juniper_warp::graphiql_filter("/graphql", Some("/subscriptions")));
+
+    let playground = warp::path("playground")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(juniper_warp::playground_filter("/graphql", Some("/subscriptions")));
+
+    // The token is read from the headers of the upgrade request, or from the `connection_init` payload
+    let coordinator = Arc::new(juniper_subscriptions::Coordinator::new(gql::schema()));
+    let subscription_auth = auth.clone();
+    let subscriptions = warp::path("subscriptions")
+        .and(warp::ws())
+        .and(filters::with_db(db.clone()))
+        .and(auth::optional(auth.clone()))
+        .and(events::with_events(events.clone()))
+        .map(move |ws: warp::ws::Ws, db: models::Db, claims: Option<auth::Claims>, events: events::Events| {
+            let connection = subscriptions::Connection {
+                coordinator: coordinator.clone(),
+                schema: schema.clone(),
+                limits: limits.clone(),
+                persisted: persisted.clone(),
+                auth: subscription_auth.clone(),
+                db,
+                claims,
+                events,
+            };
+            ws.on_upgrade(move |websocket| connection.serve(websocket))
+        })
+        .map(|reply| warp::reply::with_header(reply, "Sec-WebSocket-Protocol", "graphql-ws"));
 
     // Define api filter
-    let api = filters::todos(db);
+    let api = filters::rest_todos(db.clone(), auth.clone())
+        .or(filters::todos_events(events, auth.clone()))
+        .or(filters::rest_api_keys(db.clone(), auth.clone()))
+        .or(filters::audit_list(db, auth));
 
 
     // Define root of all our routes
     let routes = api;
 
 
-    let routes = routes.or(warp::path("graphql").and(graphql_filter));
+    let routes = routes.or(warp::path("graphql").and(warp::path::end()).and(graphql_filter))
+        .or(schema_sdl)
+        .or(graphiql)
+        .or(playground)
+        .or(subscriptions)
+        .recover(handlers::handle_rejection);
 
     // Start server
     warp::serve(routes).run(([192, 168, 0, 10], 3030)).await;
 }
 
-mod filters {
-    use super::handlers;
-    use super::models::{Db, ListOptions, Todo};
-    use warp::Filter;
+/// The GraphQL transport of `/graphql`: one operation or a batch of them, in a JSON POST body
+/// or in the query string of a GET, or a lone document posted as `application/graphql`.
+/// The document of every operation is looked up in the persisted queries when the client
+/// only sends its hash, and checked against the `Limits`.
+mod graphql {
+    use super::gql::{requested_todos, Context, Schema};
+    use super::limits::{Budget, LimitError, Limits};
+    use super::persisted::PersistedQueries;
+    use bytes::Bytes;
+    use juniper::http::{GraphQLRequest, GraphQLResponse};
+    use juniper::{FieldError, InputValue};
+    use serde::Deserialize;
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use warp::filters::BoxedFilter;
+    use warp::http::StatusCode;
+    use warp::{Filter, Rejection};
+
+    /// Largest accepted body, a whole batch included.
+    const MAX_BODY: u64 = 1024 * 64;
+    /// Most operations in a batch.
+    const MAX_BATCH: usize = 10;
+
+    /// One operation, as sent by the clients.
+    #[derive(Debug, Default, Deserialize)]
+    pub struct Payload {
+        pub query: Option<String>,
+        #[serde(rename = "operationName")]
+        pub operation_name: Option<String>,
+        /// Some clients send the variables as a JSON string
+        pub variables: Option<Value>,
+        /// Holds the `persistedQuery` hash, also maybe a JSON string
+        pub extensions: Option<Value>,
+    }
 
-    /// The 4 TODOs filters combined.
-    pub fn todos(db: Db,) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        todos_list(db.clone())
-            .or(todos_create(db.clone()))
-            .or(todos_update(db.clone()))
-            .or(todos_delete(db))
+    // A lone operation could also be read from an array, so the batch is tried first
+    #[derive(Debug, Deserialize)]
+    #[serde(untagged)]
+    pub enum Batch {
+        Many(Vec<Payload>),
+        Single(Payload),
     }
 
-    /// GET /todos?offset=3&limit=5
-    pub fn todos_list(db: Db) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path("todos")
-            .and(warp::get())
-            .and(warp::query::<ListOptions>())
-            .and(with_db(db))
-            .and_then(handlers::list_todos)
+    pub fn graphql_filter(schema: Arc<Schema>, context: BoxedFilter<(Context,)>, limits: Arc<Limits>, persisted: Arc<PersistedQueries>) -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
+        let document = warp::header::optional::<String>("content-type")
+            .and_then(|content_type: Option<String>| async move {
+                match content_type {
+                    Some(content_type) if is_graphql(&content_type) => Ok(()),
+                    _ => Err(warp::reject::not_found()),
+                }
+            })
+            .untuple_one()
+            .and(warp::body::bytes())
+            .map(|body: Bytes| Batch::Single(Payload {
+                query: Some(String::from_utf8_lossy(&body).into_owned()),
+                ..Payload::default()
+            }));
+        let post = warp::post()
+            .and(warp::body::content_length_limit(MAX_BODY))
+            .and(document.or(warp::body::json::<Batch>()).unify());
+        let get = warp::get()
+            .and(warp::query::<HashMap<String, String>>())
+            .map(|params: HashMap<String, String>| Batch::Single(Payload {
+                query: params.get("query").cloned(),
+                operation_name: params.get("operationName").cloned(),
+                variables: params.get("variables").cloned().map(Value::String),
+                extensions: params.get("extensions").cloned().map(Value::String),
+            }));
+        post.or(get).unify()
+            .and(context)
+            .and_then(move |batch: Batch, context: Context| execute(schema.clone(), limits.clone(), persisted.clone(), batch, context))
     }
 
-    /// POST /todos with JSON body
-    pub fn todos_create(db: Db) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("todos")
-            .and(warp::post())
-            .and(json_body())
-            .and(with_db(db))
-            .and_then(handlers::create_todos)
+    // Answers 400 unless every operation succeeded, as juniper_warp does
+    async fn execute(schema: Arc<Schema>, limits: Arc<Limits>, persisted: Arc<PersistedQueries>, batch: Batch, context: Context) -> Result<impl warp::Reply, Rejection> {
+        let mut budget = Budget::default();
+        let (ok, body) = match batch {
+            Batch::Single(payload) => run(&schema, prepare(&schema, &limits, &persisted, payload, &mut budget).await, &context).await,
+            Batch::Many(payloads) if payloads.len() > MAX_BATCH => {
+                let message = format!("BATCH_TOO_LARGE: at most {} operations are run together", MAX_BATCH);
+                failure(request_error("BATCH_TOO_LARGE", message))
+            }
+            // Every operation is measured before any of them runs, against the budget of the whole batch
+            Batch::Many(payloads) => {
+                let mut requests = Vec::with_capacity(payloads.len());
+                for payload in payloads {
+                    requests.push(prepare(&schema, &limits, &persisted, payload, &mut budget).await);
+                }
+                // One after the other, a mutation sees what the previous operations wrote
+                let mut responses = Vec::with_capacity(requests.len());
+                for request in requests {
+                    responses.push(run(&schema, request, &context).await);
+                }
+                let ok = responses.iter().all(|(ok, _)| *ok);
+                (ok, Value::Array(responses.into_iter().map(|(_, body)| body).collect()))
+            }
+        };
+        let status = if ok { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+        Ok(warp::reply::with_status(warp::reply::json(&body), status))
     }
 
-    /// PUT /todos/:id with JSON BODY
-    pub fn todos_update(db: Db) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("todos" / i32)
-            .and(warp::put())
-            .and(json_body())
-            .and(with_db(db))
-            .and_then(handlers::update_todo)
+    async fn run(schema: &Schema, request: Result<Prepared, FieldError>, context: &Context) -> (bool, Value) {
+        let Prepared { request, todos } = match request {
+            Ok(prepared) => prepared,
+            Err(e) => return failure(e),
+        };
+        context.prefetch(&todos).await;
+        let response = request.execute(schema, context).await;
+        (response.is_ok(), serde_json::to_value(&response).unwrap_or_default())
     }
 
-    /// DELETE /todos/:id
-    pub fn todos_delete(db: Db) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        // we'll make one of our endpoints admin-only to show how authentification filters are used
-        let admin_only = warp::header::exact("authorization", "Bearer admin");
+    /// One operation, ready to run.
+    pub struct Prepared {
+        pub request: GraphQLRequest,
+        /// The todos its `todo` and `node` fields ask for
+        pub todos: Vec<i32>,
+    }
 
-        warp::path!("todos" / i32)
-            .and(admin_only)
-            .and(warp::delete())
-            .and(with_db(db))
-            .and_then(handlers::delete_todo)
+    /// The request of one operation, once its document is known and measured.
+    /// Its field count and cost are charged to `budget`.
+    pub async fn prepare(schema: &Schema, limits: &Limits, persisted: &PersistedQueries, payload: Payload, budget: &mut Budget) -> Result<Prepared, FieldError> {
+        let extensions = json_parameter(payload.extensions, "INVALID_EXTENSIONS")?;
+        let query = persisted.resolve(payload.query, &extensions).await
+            .map_err(|e| request_error(e.code(), e.to_string()))?;
+        let variables = match json_parameter(payload.variables, "INVALID_VARIABLES")? {
+            Value::Null => None,
+            variables => Some(serde_json::from_value::<InputValue>(variables)
+                .map_err(|e| request_error("INVALID_VARIABLES", format!("INVALID_VARIABLES: {}", e)))?),
+        };
+        limits.check(schema, &query, payload.operation_name.as_deref(), variables.as_ref(), budget)
+            .map_err(limit_error)?;
+        let todos = requested_todos(schema, &query, payload.operation_name.as_deref(), variables.as_ref());
+        Ok(Prepared { request: GraphQLRequest::new(query, payload.operation_name, variables), todos })
     }
 
+    // Parameters such as `charset` may follow the type
+    fn is_graphql(content_type: &str) -> bool {
+        content_type.split(';').next().map_or(false, |mime| mime.trim().eq_ignore_ascii_case("application/graphql"))
+    }
 
-    /// Make the db accessible within filter
-    pub fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = std::convert::Infallible> + Clone {
-        warp::any().map(move || db.clone())
+    /// A JSON parameter, which may have been sent as a JSON string.
+    fn json_parameter(value: Option<Value>, code: &'static str) -> Result<Value, FieldError> {
+        match value {
+            Some(Value::String(value)) => serde_json::from_str(&value)
+                .map_err(|e| request_error(code, format!("{}: {}", code, e))),
+            value => Ok(value.unwrap_or(Value::Null)),
+        }
     }
 
-    fn json_body() -> impl Filter<Extract= (Todo,), Error = warp::Rejection> + Clone {
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    fn failure(error: FieldError) -> (bool, Value) {
+        let response: GraphQLResponse = GraphQLResponse::error(error);
+        (false, serde_json::to_value(&response).unwrap_or_default())
+    }
+
+    /// An error refusing the whole operation, with its code in the extensions.
+    pub fn request_error(code: &'static str, message: String) -> FieldError {
+        let mut extensions = juniper::Object::with_capacity(1);
+        extensions.add_field("code", juniper::Value::scalar(code));
+        FieldError::new(message, juniper::Value::object(extensions))
+    }
+
+    fn limit_error(e: LimitError) -> FieldError {
+        let (limit, actual) = match &e {
+            LimitError::Syntax(_) => return request_error(e.code(), e.to_string()),
+            LimitError::Over { limit, actual, .. } => (*limit, *actual),
+        };
+        let number = |n: u64| juniper::Value::scalar(i32::try_from(n).unwrap_or(i32::MAX));
+        let mut extensions = juniper::Object::with_capacity(3);
+        extensions.add_field("code", juniper::Value::scalar(e.code()));
+        extensions.add_field("limit", number(limit));
+        extensions.add_field("actual", number(actual));
+        FieldError::new(e.to_string(), juniper::Value::object(extensions))
     }
 }
 
+/// The graphql-ws protocol of `/subscriptions`: the client starts the connection with
+/// `connection_init`, then starts and stops subscriptions by id, and gets their results
+/// as `data` messages until `complete`.
+mod subscriptions {
+    use super::auth::{Auth, AuthError, Claims, TOKEN_PARAMETER};
+    use super::events::Events;
+    use super::gql::{Context, Mutation, Query, Schema, Subscription};
+    use super::graphql::{prepare, request_error, Payload};
+    use super::limits::{Budget, Limits};
+    use super::models::Db;
+    use super::persisted::PersistedQueries;
+    use futures::future::{self, AbortHandle};
+    use futures::{FutureExt, StreamExt};
+    use juniper::http::{GraphQLRequest, GraphQLResponse};
+    use juniper::{DefaultScalarValue, FieldError, SubscriptionCoordinator};
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use warp::ws::{Message, WebSocket};
 
-/// These are ou API handlers, the ends of each filter chain.
-/// Notice how thanks to using `Filter::and`, we can define a function
-/// with the exact arguments we'd expect from each filter in the chain.
-/// No tuples are needed, it's auto flattened for the functions.
-mod handlers {
-    use super::models::{Db, ListOptions, Todo, db_list_todos, db_create_todos, db_update_todo, db_delete_todo};
-    use std::convert::Infallible;
-    use warp::http::StatusCode;
+    pub type Coordinator = juniper_subscriptions::Coordinator<'static, Query, Mutation, Subscription, Context, DefaultScalarValue>;
 
-    pub async fn list_todos(opts: ListOptions, db: Db) -> Result<impl warp::Reply, Infallible> {
-        let todos_list = db_list_todos(opts.offset.unwrap_or(0), opts.limit.unwrap_or(std::i32::MAX), &db).await;
-        Ok(warp::reply::json(&todos_list))
+    type Sender = mpsc::UnboundedSender<Message>;
+
+    /// A message of the client.
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum ClientMessage {
+        ConnectionInit {
+            #[serde(default)]
+            payload: Value,
+        },
+        Start {
+            id: String,
+            payload: Payload,
+        },
+        Stop {
+            id: String,
+        },
+        ConnectionTerminate,
     }
 
-    pub async fn create_todos(create: Todo, db: Db) -> Result<impl warp::Reply, Infallible> {
-        let row = db_create_todos(create.id, create.text, create.completed, &db).await;
-        if row != 0 {
-            Ok(StatusCode::CREATED)
-        } else {
-            Ok(StatusCode::BAD_REQUEST)
-        }
+    /// One WebSocket, before its upgrade.
+    pub struct Connection {
+        pub coordinator: Arc<Coordinator>,
+        /// Every subscription is checked as a request of its own
+        pub schema: Arc<Schema>,
+        pub limits: Arc<Limits>,
+        /// Only the documents of the allowlist start, when there is one
+        pub persisted: Arc<PersistedQueries>,
+        pub auth: Auth,
+        pub db: Db,
+        /// The caller from the headers of the upgrade request
+        pub claims: Option<Claims>,
+        pub events: Events,
     }
 
-    pub async fn update_todo(id: i32, update: Todo, db: Db) -> Result<impl warp::Reply, Infallible> {
-        let rows = db_update_todo(id, update.text, update.completed, &db).await;
-        if rows != 0 {
-            Ok(StatusCode::OK)
-        } else {
-            Ok(StatusCode::NOT_FOUND)
+    impl Connection {
+        pub async fn serve(self, websocket: WebSocket) {
+            let (sink, mut incoming) = websocket.split();
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(receiver.map(Ok).forward(sink).map(|r| {
+                if let Err(e) = r {
+                    eprintln!("websocket error: {}", e);
+                }
+            }));
+            // The caller, once `connection_init` has been accepted
+            let mut caller: Option<Option<Claims>> = None;
+            let mut running: HashMap<String, AbortHandle> = HashMap::new();
+            while let Some(message) = incoming.next().await {
+                let message = match message {
+                    Ok(message) if message.is_close() => break,
+                    Ok(message) => message,
+                    Err(e) => {
+                        eprintln!("websocket error: {}", e);
+                        break;
+                    }
+                };
+                // Pings and binary messages
+                let text = match message.to_str() {
+                    Ok(text) => text,
+                    Err(_) => continue,
+                };
+                let message = match serde_json::from_str::<ClientMessage>(text) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        send(&sender, json!({ "type": "connection_error", "payload": { "message": format!("INVALID_MESSAGE: {}", e) } }));
+                        continue;
+                    }
+                };
+                match message {
+                    ClientMessage::ConnectionInit { payload } => match self.identify(&payload).await {
+                        Ok(claims) => {
+                            caller = Some(claims);
+                            send(&sender, json!({ "type": "connection_ack" }));
+                            send(&sender, json!({ "type": "ka" }));
+                        }
+                        Err(e) => {
+                            send(&sender, json!({ "type": "connection_error", "payload": { "message": e.to_string() } }));
+                            break;
+                        }
+                    },
+                    ClientMessage::Start { id, payload } => {
+                        let claims = match &caller {
+                            Some(claims) => claims.clone(),
+                            None => {
+                                let message = "NOT_INITIALIZED: connection_init must come first".to_string();
+                                send_error(&sender, &id, request_error("NOT_INITIALIZED", message));
+                                continue;
+                            }
+                        };
+                        let request = match prepare(&self.schema, &self.limits, &self.persisted, payload, &mut Budget::default()).await {
+                            Ok(prepared) => prepared.request,
+                            Err(e) => {
+                                send_error(&sender, &id, e);
+                                continue;
+                            }
+                        };
+                        let context = Context::new(self.db.clone(), claims, self.events.clone());
+                        let (task, handle) = future::abortable(run(self.coordinator.clone(), request, context, id.clone(), sender.clone()));
+                        tokio::spawn(task);
+                        // A client reusing an id replaces its subscription
+                        if let Some(previous) = running.insert(id, handle) {
+                            previous.abort();
+                        }
+                    }
+                    ClientMessage::Stop { id } => {
+                        if let Some(handle) = running.remove(&id) {
+                            handle.abort();
+                            send(&sender, json!({ "type": "complete", "id": id }));
+                        }
+                    }
+                    ClientMessage::ConnectionTerminate => break,
+                }
+            }
+            for handle in running.values() {
+                handle.abort();
+            }
+        }
+
+        /// The caller of the `connection_init` payload, else the one of the headers.
+        async fn identify(&self, payload: &Value) -> Result<Option<Claims>, AuthError> {
+            let field = |name: &str| -> Option<String> {
+                payload.as_object()?.iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .and_then(|(_, value)| value.as_str())
+                    .map(String::from)
+            };
+            let authorization = field("authorization")
+                .or_else(|| field(TOKEN_PARAMETER).map(|token| format!("Bearer {}", token)));
+            let api_key = field("x-api-key");
+            if authorization.is_none() && api_key.is_none() {
+                return Ok(self.claims.clone());
+            }
+            self.auth.identify(authorization, api_key).await
         }
     }
 
-    pub async fn delete_todo(id: i32, db: Db) -> Result<impl warp::Reply, Infallible> {
-        let rows = db_delete_todo(id, &db).await;
-        if rows != 0 {
-            Ok(StatusCode::NO_CONTENT)
-        } else {
-            Ok(StatusCode::NOT_FOUND)
+    /// Send the results of a subscription until its stream ends.
+    async fn run(coordinator: Arc<Coordinator>, request: GraphQLRequest, context: Context, id: String, sender: Sender) {
+        match coordinator.subscribe(&request, &context).await {
+            Ok(mut results) => {
+                while let Some(result) = results.next().await {
+                    let payload = serde_json::to_value(&result).unwrap_or_default();
+                    send(&sender, json!({ "type": "data", "id": id, "payload": payload }));
+                }
+                send(&sender, json!({ "type": "complete", "id": id }));
+            }
+            Err(e) => {
+                let errors = serde_json::to_value(&e).unwrap_or_default();
+                send(&sender, json!({ "type": "error", "id": id, "payload": errors }));
+            }
         }
     }
+
+    fn send_error(sender: &Sender, id: &str, error: FieldError) {
+        let response: GraphQLResponse = GraphQLResponse::error(error);
+        let mut response = serde_json::to_value(&response).unwrap_or_default();
+        send(sender, json!({ "type": "error", "id": id, "payload": response["errors"].take() }));
+    }
+
+    // The other half of the socket is gone when this fails, the loop is about to end
+    fn send(sender: &Sender, message: Value) {
+        let _ = sender.send(Message::text(message.to_string()));
+    }
 }
 
-mod models {
-    use serde::{Deserialize, Serialize};
-    use sqlx::PgPool;
+/// Depth, field count and cost limits of the GraphQL operations, checked before they run
+/// so a single client can't exhaust the database pool. The limits come from the environment:
+///
+/// - `GRAPHQL_MAX_DEPTH`: how deep the selections can nest, 10 by default
+/// - `GRAPHQL_MAX_FIELDS`: how many fields a request can select, 200 by default
+/// - `GRAPHQL_MAX_COST`: the budget of a request, 1000 by default. Every field costs 1,
+///   and what is selected under a list field is paid for every todo of the page.
+///   `clearCompleted` returns every completed todo, it is paid for as a full page of 100.
+///
+/// The operations of a batch share the field count and the cost of their request. The documents
+/// are parsed by juniper, fragments are expanded and introspection fields are free. A document
+/// which can't be parsed is refused here, so nothing unmeasured ever runs.
+mod limits {
+    use super::gql::{operation, variable_values, Schema};
+    use super::models::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+    use juniper::parser::parse_document_source;
+    use juniper::{DefaultScalarValue, Definition, InputValue, Selection, Spanning};
+    use std::collections::HashMap;
     use std::env;
+    use std::fmt;
+
+    /// The fields returning a page of todos or of audit entries.
+    const LIST_FIELDS: [&str; 3] = ["todos", "todosList", "history"];
+    /// The fields returning every matching todo, paid for as a full page.
+    const UNPAGED_LIST_FIELDS: [&str; 1] = ["clearCompleted"];
+    /// The arguments giving the size of a page, possibly inside an input object.
+    const SIZE_ARGUMENTS: [&str; 3] = ["limit", "first", "last"];
 
-    // So we don't have to tackle how different database work, we'll just use
-    // a simple in-memory DB, a vector synchronized by Mutex
-    //1 pub type Db = Arc<Mutex<PgPool>>;
-    pub type Db = PgPool;
+    type Value = InputValue<DefaultScalarValue>;
+    type Variables<'a> = HashMap<&'a str, &'a Value>;
 
-    #[derive(Debug, Deserialize, Serialize, Clone)] //, sqlx::FromRow
-    #[derive(juniper::GraphQLObject)]
-    pub struct Todo {
-        pub id: i32,
-        pub text: String,
-        pub completed: bool
+    pub struct Limits {
+        pub max_depth: u64,
+        pub max_fields: u64,
+        pub max_cost: u64,
     }
 
-    #[derive(juniper::GraphQLInputObject)]
-    #[graphql(description="A todo list")]
-    pub struct NewTodo {
-        pub text: String,
-        pub completed: bool
+    /// What the operations of a request have spent so far.
+    #[derive(Debug, Default)]
+    pub struct Budget {
+        fields: u64,
+        cost: u64,
     }
 
-    // The query parameters for list_todos.
-    #[derive(Debug, Deserialize)]
-    #[derive(juniper::GraphQLInputObject)]
-    pub struct ListOptions {
-        pub offset: Option<i32>,
-        pub limit: Option<i32>,
+    impl Limits {
+        pub fn from_env() -> Result<Limits, String> {
+            Ok(Limits {
+                max_depth: setting("GRAPHQL_MAX_DEPTH", 10)?,
+                max_fields: setting("GRAPHQL_MAX_FIELDS", 200)?,
+                max_cost: setting("GRAPHQL_MAX_COST", 1000)?,
+            })
+        }
+
+        /// Measure the operation `operation_name` of `query` with the given variables, and charge
+        /// it to the `budget` of its request. Nothing is charged when the operation is refused.
+        pub fn check(&self, schema: &Schema, query: &str, operation_name: Option<&str>, variables: Option<&Value>, budget: &mut Budget) -> Result<(), LimitError> {
+            let document = parse_document_source(query, &schema.schema).map_err(|e| {
+                LimitError::Syntax(format!("{} at line {}, column {}", e.item, e.start.line() + 1, e.start.column() + 1))
+            })?;
+            // Juniper refuses it without running anything
+            let operation = match operation(&document, operation_name) {
+                Some(operation) => operation,
+                None => return Ok(()),
+            };
+
+            let values = variable_values(operation, variables);
+            let fragments = document.iter().filter_map(|definition| match definition {
+                Definition::Fragment(fragment) => Some((fragment.item.name.item, &fragment.item.selection_set)),
+                Definition::Operation(_) => None,
+            }).collect();
+            let mut analysis = Analysis { max_depth: self.max_depth, variables: &values, fragments, measured: HashMap::new(), visiting: Vec::new() };
+            let measure = analysis.selections(&operation.selection_set, 0)?;
+
+            if measure.depth > self.max_depth {
+                return Err(LimitError::Over { code: "QUERY_TOO_DEEP", what: "depth", limit: self.max_depth, actual: measure.depth });
+            }
+            let fields = budget.fields.saturating_add(measure.fields);
+            if fields > self.max_fields {
+                return Err(LimitError::Over { code: "QUERY_TOO_MANY_FIELDS", what: "field count", limit: self.max_fields, actual: fields });
+            }
+            let cost = budget.cost.saturating_add(measure.cost);
+            if cost > self.max_cost {
+                return Err(LimitError::Over { code: "QUERY_TOO_COMPLEX", what: "cost", limit: self.max_cost, actual: cost });
+            }
+            *budget = Budget { fields, cost };
+            Ok(())
+        }
+    }
+
+    fn setting(name: &str, default: u64) -> Result<u64, String> {
+        match env::var(name) {
+            Ok(value) => value.parse().map_err(|_| format!("{} must be a positive number, not {}", name, value)),
+            Err(_) => Ok(default),
+        }
+    }
+
+    /// A document which can't be measured, or a request over one of the limits.
+    #[derive(Debug)]
+    pub enum LimitError {
+        Syntax(String),
+        Over {
+            code: &'static str,
+            what: &'static str,
+            limit: u64,
+            actual: u64,
+        },
+    }
+
+    impl LimitError {
+        pub fn code(&self) -> &'static str {
+            match self {
+                LimitError::Syntax(_) => "GRAPHQL_PARSE_FAILED",
+                LimitError::Over { code, .. } => *code,
+            }
+        }
     }
 
-    pub async fn blank_db() -> Db {
-        dotenv::dotenv().ok();
-        //Arc::new(Mutex::new(Vec::new()))
-        //let pool = SqlitePool::new("sqlite:///Users/akersof/CLionProjects/warp-tutorial/todos.db").await.unwrap();
-        let pool = PgPool::builder().max_size(10).build(&env::var("DATABASE_URL").unwrap()).await.unwrap();
-        //1 Arc::new(Mutex::new(pool))
-        pool
+    impl fmt::Display for LimitError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                LimitError::Syntax(reason) => write!(f, "GRAPHQL_PARSE_FAILED: {}", reason),
+                LimitError::Over { code, what, limit, actual } => {
+                    write!(f, "{}: the {} of the request is {}, over the limit of {}", code, what, actual, limit)
+                }
+            }
+        }
     }
 
-    // Here perform various known request, they will be called by the corresponding handler
-    pub async fn db_list_todos(offset: i32, limit: i32, db: &Db) -> Vec<Todo> {
-        let todos_list = sqlx::query_as!(Todo, "SELECT * FROM todos WHERE id BETWEEN $1 and $2", offset, limit)
-            .fetch_all(db).await.unwrap();
-        todos_list
+    #[derive(Debug, Clone, Copy, Default)]
+    struct Measure {
+        depth: u64,
+        fields: u64,
+        cost: u64,
     }
 
-    pub async fn db_create_todos(id: i32, text: String, completed: bool, db: &Db) -> u64 {
-        let rows = sqlx::query!("INSERT INTO todos VALUES($1, $2, $3)", id, text, completed)
-            .execute(db).await.unwrap();
-        rows
+    struct Analysis<'a> {
+        max_depth: u64,
+        variables: &'a Variables<'a>,
+        fragments: HashMap<&'a str, &'a Vec<Selection<'a, DefaultScalarValue>>>,
+        /// The measures of the fragments, which are spread more than once
+        measured: HashMap<&'a str, Measure>,
+        visiting: Vec<&'a str>,
     }
 
-    pub async fn db_update_todo(id: i32, text: String, completed: bool, db: &Db) -> u64 {
-        let rows = sqlx::query!("UPDATE todos SET text = $1, completed = $2 where id = $3", text, completed, id)
-            .execute(db).await.unwrap();
-        rows
+    impl<'a> Analysis<'a> {
+        fn selections(&mut self, selections: &'a [Selection<'a, DefaultScalarValue>], level: u64) -> Result<Measure, LimitError> {
+            // Deeper is refused anyway, and a long chain of fragments must not overflow the stack
+            if level > self.max_depth {
+                return Err(LimitError::Over { code: "QUERY_TOO_DEEP", what: "depth", limit: self.max_depth, actual: level });
+            }
+            let mut total = Measure::default();
+            for selection in selections {
+                let measure = match selection {
+                    Selection::Field(field) if field.item.name.item.starts_with("__") => continue,
+                    Selection::Field(field) => {
+                        let field = &field.item;
+                        let children = match &field.selection_set {
+                            Some(selections) => self.selections(selections, level + 1)?,
+                            None => Measure::default(),
+                        };
+                        let size = if LIST_FIELDS.contains(&field.name.item) {
+                            let arguments = field.arguments.as_ref().map_or(&[][..], |arguments| &arguments.item.items[..]);
+                            self.page_size(arguments)
+                        } else if UNPAGED_LIST_FIELDS.contains(&field.name.item) {
+                            MAX_PAGE_SIZE as u64
+                        } else {
+                            1
+                        };
+                        Measure {
+                            depth: children.depth + 1,
+                            fields: children.fields.saturating_add(1),
+                            cost: children.cost.saturating_mul(size).saturating_add(1),
+                        }
+                    }
+                    Selection::InlineFragment(fragment) => self.selections(&fragment.item.selection_set, level)?,
+                    Selection::FragmentSpread(spread) => self.fragment(spread.item.name.item, level)?,
+                };
+                total.depth = total.depth.max(measure.depth);
+                total.fields = total.fields.saturating_add(measure.fields);
+                total.cost = total.cost.saturating_add(measure.cost);
+            }
+            Ok(total)
+        }
+
+        fn fragment(&mut self, name: &'a str, level: u64) -> Result<Measure, LimitError> {
+            if let Some(measure) = self.measured.get(name) {
+                return Ok(*measure);
+            }
+            // Unknown and cyclic fragments are refused by juniper
+            let selections = match self.fragments.get(name) {
+                Some(selections) if !self.visiting.contains(&name) => *selections,
+                _ => return Ok(Measure::default()),
+            };
+            self.visiting.push(name);
+            let measure = self.selections(selections, level)?;
+            self.visiting.pop();
+            self.measured.insert(name, measure);
+            Ok(measure)
+        }
+
+        /// The number of items a list field returns, the server never returns more than `MAX_PAGE_SIZE`.
+        fn page_size(&self, arguments: &'a [(Spanning<&'a str>, Spanning<Value>)]) -> u64 {
+            let size = arguments.iter().find_map(|(name, argument)| {
+                match self.resolve(&argument.item) {
+                    Some(InputValue::Object(fields)) => fields.iter()
+                        .find(|(name, _)| SIZE_ARGUMENTS.contains(&name.item.as_str()))
+                        .and_then(|(_, value)| self.int(&value.item)),
+                    Some(value) if SIZE_ARGUMENTS.contains(&name.item) => self.int(value),
+                    _ => None,
+                }
+            });
+            size.map_or(DEFAULT_PAGE_SIZE, i64::from).max(1).min(MAX_PAGE_SIZE) as u64
+        }
+
+        fn resolve(&self, value: &'a Value) -> Option<&'a Value> {
+            match value {
+                InputValue::Variable(name) => self.variables.get(name.as_str()).copied(),
+                value => Some(value),
+            }
+        }
+
+        fn int(&self, value: &'a Value) -> Option<i32> {
+            match self.resolve(value)? {
+                InputValue::Scalar(DefaultScalarValue::Int(n)) => Some(*n),
+                _ => None,
+            }
+        }
     }
 
-    pub async fn db_delete_todo(id: i32, db: &Db) -> u64 {
-        let rows = sqlx::query!("DELETE FROM todos WHERE id = $1", id)
-            .execute(db).await.unwrap();
-        rows
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use super::super::gql::schema;
+
+        fn limits() -> Limits {
+            Limits { max_depth: 10, max_fields: 200, max_cost: 1000 }
+        }
+
+        fn variables(json: &str) -> Value {
+            serde_json::from_str(json).unwrap()
+        }
+
+        fn check(limits: &Limits, query: &str, variables: Option<&Value>) -> Result<Budget, LimitError> {
+            let mut budget = Budget::default();
+            limits.check(&schema(), query, None, variables, &mut budget).map(|_| budget)
+        }
+
+        #[test]
+        fn list_fields_pay_for_their_page() {
+            let budget = check(&limits(), "{ todosList(opt: { limit: 50 }) { id text } }", None).unwrap();
+            assert_eq!((budget.fields, budget.cost), (3, 101));
+            let budget = check(&limits(), "{ todosList(opt: {}) { id text } }", None).unwrap();
+            assert_eq!(budget.cost, 41);
+        }
+
+        #[test]
+        fn over_the_limits() {
+            let deep = "{ todos { edges { node { history { id } } } } }";
+            let limits = Limits { max_depth: 4, ..limits() };
+            assert_eq!(check(&limits, deep, None).unwrap_err().code(), "QUERY_TOO_DEEP");
+
+            let limits = Limits { max_fields: 2, ..limits };
+            assert_eq!(check(&limits, "{ apiVersion a: apiVersion b: apiVersion }", None).unwrap_err().code(), "QUERY_TOO_MANY_FIELDS");
+
+            let costly = "{ todosList(opt: { limit: 100 }) { id text completed version ownerId databaseId } }";
+            let e = check(&Limits { max_cost: 500, ..self::limits() }, costly, None).unwrap_err();
+            assert_eq!(e.code(), "QUERY_TOO_COMPLEX");
+            assert_eq!(e.to_string(), "QUERY_TOO_COMPLEX: the cost of the request is 601, over the limit of 500");
+        }
+
+        #[test]
+        fn fragments_are_expanded() {
+            let spread = "query { todosList(opt: { limit: 100 }) { ...todo } } fragment todo on Todo { id text }";
+            let inline = "query { todosList(opt: { limit: 100 }) { ... on Todo { id text } } }";
+            let plain = "query { todosList(opt: { limit: 100 }) { id text } }";
+            for query in &[spread, inline, plain] {
+                let budget = check(&limits(), query, None).unwrap();
+                assert_eq!((budget.fields, budget.cost), (3, 201), "{}", query);
+            }
+            // Spread twice, paid twice
+            let twice = "{ a: todosList(opt: { limit: 100 }) { ...todo } b: todosList(opt: { limit: 100 }) { ...todo } } fragment todo on Todo { id text }";
+            assert_eq!(check(&limits(), twice, None).unwrap().cost, 402);
+        }
+
+        #[test]
+        fn every_alias_is_paid_for() {
+            let aliases: String = (0..5).map(|n| format!("a{}: todosList(opt: {{ limit: 100 }}) {{ id text }} ", n)).collect();
+            let e = check(&limits(), &format!("{{ {} }}", aliases), None).unwrap_err();
+            assert_eq!(e.code(), "QUERY_TOO_COMPLEX");
+        }
+
+        #[test]
+        fn sizes_from_the_variables() {
+            let query = "query Page($n: Int, $opt: ListOptions) { todos(first: $n) { edges { node { id } } } todosList(opt: $opt) { id } }";
+            let budget = check(&limits(), query, Some(&variables(r#"{"n": 100, "opt": {"limit": 50}}"#))).unwrap();
+            assert_eq!(budget.cost, 1 + 100 * 3 + 1 + 50);
+            // The defaults of the variables, then the default page size
+            let query = "query Page($n: Int = 100) { todos(first: $n) { edges { node { id } } } }";
+            assert_eq!(check(&limits(), query, None).unwrap().cost, 301);
+            assert_eq!(check(&limits(), query, Some(&variables(r#"{"n": null}"#))).unwrap().cost, 61);
+            // Never more than a full page
+            let query = "{ todosList(opt: { limit: 100000 }) { id } }";
+            assert_eq!(check(&limits(), query, None).unwrap().cost, 101);
+        }
+
+        #[test]
+        fn history_is_paid_for_its_page() {
+            let query = "{ todo(id: 1) { history { action } } }";
+            assert_eq!(check(&limits(), query, None).unwrap().cost, 1 + 20 + 1);
+            let query = "{ todo(id: 1) { history(last: 100) { action actor } } }";
+            assert_eq!(check(&limits(), query, None).unwrap().cost, 1 + 200 + 1);
+        }
+
+        #[test]
+        fn clear_completed_is_paid_for_a_full_page() {
+            let query = "mutation { clearCompleted { id text } }";
+            assert_eq!(check(&limits(), query, None).unwrap().cost, 2 * 100 + 1);
+        }
+
+        #[test]
+        fn batches_share_the_budget() {
+            let limits = Limits { max_cost: 250, ..limits() };
+            let query = "{ todosList(opt: { limit: 100 }) { id } }";
+            let mut budget = Budget::default();
+            limits.check(&schema(), query, None, None, &mut budget).unwrap();
+            limits.check(&schema(), query, None, None, &mut budget).unwrap();
+            let e = limits.check(&schema(), query, None, None, &mut budget).unwrap_err();
+            assert_eq!(e.to_string(), "QUERY_TOO_COMPLEX: the cost of the request is 303, over the limit of 250");
+            assert_eq!(budget.cost, 202);
+        }
+
+        #[test]
+        fn malformed_documents_are_refused() {
+            for query in &["{ todosList(opt: { limit: 100 }) { id ", "{ todos(first: ) { id } }", "query { todo(id: \"1) { id } }"] {
+                let e = check(&limits(), query, None).unwrap_err();
+                assert_eq!(e.code(), "GRAPHQL_PARSE_FAILED", "{}", query);
+            }
+        }
+
+        #[test]
+        fn introspection_is_free() {
+            let budget = check(&limits(), "{ __schema { types { name fields { name } } } }", None).unwrap();
+            assert_eq!((budget.fields, budget.cost), (0, 0));
+        }
     }
 }
 
-mod gql {
-    use juniper::{FieldResult, EmptyMutation ,EmptySubscription};
-    use serde::{Deserialize, Serialize};
-    use super::models::{Db, Todo, db_list_todos};
+/// Automatic persisted queries: the clients send the sha256 of a document instead of the
+/// document, and send both when the server doesn't know the hash yet. The last
+/// `APQ_CACHE_SIZE` documents are kept, 1000 by default.
+///
+/// With `GRAPHQL_ALLOWLIST` naming a manifest file, only the documents of the manifest run,
+/// subscriptions included, and nothing is registered. The manifest is an Apollo persisted query manifest, or an
+/// object whose values are the documents, the hashes are computed again in both cases.
+mod persisted {
+    use lru::LruCache;
+    use serde::Deserialize;
+    use serde_json::Value;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+    use std::env;
+    use std::fmt;
+    use std::fs;
+    use tokio::sync::Mutex;
 
-    #[derive(juniper::GraphQLInputObject)]
-    pub struct NewTodo {
-        pub text: String,
-        pub completed: bool
+    pub struct PersistedQueries {
+        /// The documents of the manifest by hash, `None` when every document may run
+        allowlist: Option<HashMap<String, String>>,
+        cache: Mutex<LruCache<String, String>>,
     }
 
+    /// The `persistedQuery` extension of a request.
     #[derive(Debug, Deserialize)]
-    #[derive(juniper::GraphQLInputObject)]
-    pub struct ListOptions {
-        pub offset: Option<i32>,
-        pub limit: Option<i32>,
+    struct PersistedQuery {
+        version: i32,
+        #[serde(rename = "sha256Hash")]
+        sha256_hash: String,
+    }
+
+    impl PersistedQueries {
+        pub fn from_env() -> Result<PersistedQueries, String> {
+            let size = match env::var("APQ_CACHE_SIZE") {
+                Ok(size) => match size.parse() {
+                    Ok(0) | Err(_) => return Err(format!("APQ_CACHE_SIZE must be a positive number, not {}", size)),
+                    Ok(size) => size,
+                },
+                Err(_) => 1000,
+            };
+            let allowlist = match env::var("GRAPHQL_ALLOWLIST") {
+                Ok(path) => Some(load_manifest(&path)?),
+                Err(_) => None,
+            };
+            Ok(PersistedQueries { allowlist, cache: Mutex::new(LruCache::new(size)) })
+        }
+
+        /// The document to run, from the query and the extensions of a request.
+        pub async fn resolve(&self, query: Option<String>, extensions: &Value) -> Result<String, PersistedError> {
+            let persisted = match extensions.get("persistedQuery") {
+                Some(persisted) => Some(serde_json::from_value::<PersistedQuery>(persisted.clone())
+                    .map_err(|e| PersistedError::Invalid(e.to_string()))?),
+                None => None,
+            };
+            // Our hashes are lowercase hex, the cache and the manifest are keyed by them
+            let hash = match persisted {
+                Some(persisted) if persisted.version != 1 => return Err(PersistedError::NotSupported),
+                Some(persisted) => Some(persisted.sha256_hash.to_ascii_lowercase()),
+                None => None,
+            };
+            match (query, hash) {
+                (None, None) => Err(PersistedError::MissingQuery),
+                (None, Some(hash)) => match &self.allowlist {
+                    Some(allowlist) => allowlist.get(&hash).cloned().ok_or(PersistedError::NotAllowed),
+                    None => self.cache.lock().await.get(&hash).cloned().ok_or(PersistedError::NotFound),
+                },
+                (Some(query), sent) => {
+                    let hash = sha256(&query);
+                    if sent.map_or(false, |sent| sent != hash) {
+                        return Err(PersistedError::HashMismatch);
+                    }
+                    match &self.allowlist {
+                        Some(allowlist) if allowlist.contains_key(&hash) => Ok(query),
+                        Some(_) => Err(PersistedError::NotAllowed),
+                        None => {
+                            self.cache.lock().await.put(hash, query.clone());
+                            Ok(query)
+                        }
+                    }
+                }
+            }
+        }
     }
 
-    pub struct Context {
-        pub pool: Db
+    fn sha256(document: &str) -> String {
+        format!("{:x}", Sha256::digest(document.as_bytes()))
     }
 
-    impl juniper::Context for Context {}
+    fn load_manifest(path: &str) -> Result<HashMap<String, String>, String> {
+        let manifest = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let manifest: Value = serde_json::from_str(&manifest)
+            .map_err(|e| format!("{} is not JSON: {}", path, e))?;
+        let documents: Vec<&Value> = match &manifest {
+            Value::Object(fields) => match fields.get("operations") {
+                Some(Value::Array(operations)) => operations.iter().map(|operation| &operation["body"]).collect(),
+                _ => fields.values().collect(),
+            },
+            _ => return Err(format!("{} must hold a JSON object", path)),
+        };
+        documents.into_iter()
+            .map(|document| match document.as_str() {
+                Some(document) => Ok((sha256(document), document.to_string())),
+                None => Err(format!("every document of {} must be a string", path)),
+            })
+            .collect()
+    }
 
-    pub struct Query;
+    #[derive(Debug)]
+    pub enum PersistedError {
+        /// The hash is not known yet, the client sends the document again
+        NotFound,
+        NotSupported,
+        NotAllowed,
+        HashMismatch,
+        MissingQuery,
+        Invalid(String),
+    }
 
-    #[juniper::graphql_object(Context = Context,)]
-    impl Query {
-        fn apiVersion() -> &str {
-            "1.0"
+    impl PersistedError {
+        pub fn code(&self) -> &'static str {
+            match self {
+                PersistedError::NotFound => "PERSISTED_QUERY_NOT_FOUND",
+                PersistedError::NotSupported => "PERSISTED_QUERY_NOT_SUPPORTED",
+                PersistedError::NotAllowed => "PERSISTED_QUERY_NOT_ALLOWED",
+                PersistedError::HashMismatch => "PERSISTED_QUERY_HASH_MISMATCH",
+                PersistedError::MissingQuery => "MISSING_QUERY",
+                PersistedError::Invalid(_) => "INVALID_PERSISTED_QUERY",
+            }
         }
+    }
 
-        async fn todosList(context: &Context, opt: ListOptions) -> FieldResult<Vec<Todo>> {
-            let res = db_list_todos(opt.offset.unwrap_or(0), opt.limit.unwrap_or(1000), &context.pool).await;
-            Ok(res)
+    impl fmt::Display for PersistedError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                // The messages the Apollo clients look for
+                PersistedError::NotFound => write!(f, "PersistedQueryNotFound"),
+                PersistedError::NotSupported => write!(f, "PersistedQueryNotSupported"),
+                PersistedError::NotAllowed => write!(f, "PERSISTED_QUERY_NOT_ALLOWED: only the documents of the allowlist run"),
+                PersistedError::HashMismatch => write!(f, "PERSISTED_QUERY_HASH_MISMATCH: the sha256Hash is not the one of the query"),
+                PersistedError::MissingQuery => write!(f, "MISSING_QUERY: the request has no query"),
+                PersistedError::Invalid(reason) => write!(f, "INVALID_PERSISTED_QUERY: {}", reason),
+            }
         }
     }
 
-    pub type Schema = juniper::RootNode<'static, Query, EmptyMutation<Context>, EmptySubscription<Context>>;
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        const TODOS: &str = "{ todos { totalCount } }";
+        const HISTORY: &str = "{ history { action } }";
+        const TODO: &str = "{ todo(id: 1) { text } }";
+
+        fn cache(size: usize) -> PersistedQueries {
+            PersistedQueries { allowlist: None, cache: Mutex::new(LruCache::new(size)) }
+        }
+
+        fn hash_only(document: &str) -> Value {
+            json!({ "persistedQuery": { "version": 1, "sha256Hash": sha256(document) } })
+        }
+
+        async fn register(persisted: &PersistedQueries, document: &str) {
+            let query = persisted.resolve(Some(document.to_string()), &hash_only(document)).await.unwrap();
+            assert_eq!(query, document);
+        }
+
+        #[tokio::test]
+        async fn unknown_hashes_are_not_found() {
+            let error = cache(10).resolve(None, &hash_only(TODOS)).await.unwrap_err();
+            assert_eq!(error.code(), "PERSISTED_QUERY_NOT_FOUND");
+            assert_eq!(error.to_string(), "PersistedQueryNotFound");
+        }
+
+        #[tokio::test]
+        async fn a_miss_registers_the_query() {
+            let persisted = cache(10);
+            assert!(persisted.resolve(None, &hash_only(TODOS)).await.is_err());
+            register(&persisted, TODOS).await;
+            assert_eq!(persisted.resolve(None, &hash_only(TODOS)).await.unwrap(), TODOS);
+            let uppercase = json!({ "persistedQuery": { "version": 1, "sha256Hash": sha256(TODOS).to_uppercase() } });
+            assert_eq!(persisted.resolve(None, &uppercase).await.unwrap(), TODOS);
+        }
+
+        #[tokio::test]
+        async fn mismatching_hashes_are_refused() {
+            let persisted = cache(10);
+            let error = persisted.resolve(Some(TODOS.to_string()), &hash_only(HISTORY)).await.unwrap_err();
+            assert_eq!(error.code(), "PERSISTED_QUERY_HASH_MISMATCH");
+            // Neither hash was registered
+            for document in &[TODOS, HISTORY] {
+                let error = persisted.resolve(None, &hash_only(document)).await.unwrap_err();
+                assert_eq!(error.code(), "PERSISTED_QUERY_NOT_FOUND");
+            }
+        }
+
+        #[tokio::test]
+        async fn only_the_allowlist_runs() {
+            let allowlist = vec![(sha256(TODOS), TODOS.to_string())].into_iter().collect();
+            let persisted = PersistedQueries { allowlist: Some(allowlist), cache: Mutex::new(LruCache::new(10)) };
+            assert_eq!(persisted.resolve(None, &hash_only(TODOS)).await.unwrap(), TODOS);
+            assert_eq!(persisted.resolve(Some(TODOS.to_string()), &Value::Null).await.unwrap(), TODOS);
+
+            let error = persisted.resolve(Some(HISTORY.to_string()), &Value::Null).await.unwrap_err();
+            assert_eq!(error.code(), "PERSISTED_QUERY_NOT_ALLOWED");
+            let error = persisted.resolve(Some(HISTORY.to_string()), &hash_only(HISTORY)).await.unwrap_err();
+            assert_eq!(error.code(), "PERSISTED_QUERY_NOT_ALLOWED");
+            // Sending the document didn't register it
+            let error = persisted.resolve(None, &hash_only(HISTORY)).await.unwrap_err();
+            assert_eq!(error.code(), "PERSISTED_QUERY_NOT_ALLOWED");
+        }
+
+        #[tokio::test]
+        async fn the_least_recently_used_is_evicted() {
+            let persisted = cache(2);
+            register(&persisted, TODOS).await;
+            register(&persisted, HISTORY).await;
+            // TODOS is now more recent than HISTORY
+            assert!(persisted.resolve(None, &hash_only(TODOS)).await.is_ok());
+            register(&persisted, TODO).await;
 
-    pub fn schema() -> Schema {
-        Schema::new(Query, EmptyMutation::<Context>::new(), EmptySubscription::<Context>::new())
+            let error = persisted.resolve(None, &hash_only(HISTORY)).await.unwrap_err();
+            assert_eq!(error.code(), "PERSISTED_QUERY_NOT_FOUND");
+            assert_eq!(persisted.resolve(None, &hash_only(TODOS)).await.unwrap(), TODOS);
+            assert_eq!(persisted.resolve(None, &hash_only(TODO)).await.unwrap(), TODO);
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,364 @@
+//! The changes made to the todos, published by every write of the REST and GraphQL APIs
+//! to the subscribers of this process.
+
+use super::models::{ApiKey, AuditEntry, AuditOptions, BulkOperation, Cursor, Db, Page, Scope, Todo, TodoError, TodoFilter, TodoRepository, TodoRows};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{future, stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "postgres")]
+use sqlx::postgres::PgListener;
+use std::collections::VecDeque;
+use std::sync::Arc;
+#[cfg(feature = "postgres")]
+use std::time::Duration;
+use tokio::sync::{broadcast, Mutex};
+use warp::Filter;
+
+/// How many events a slow subscriber can lag behind before it misses some.
+const CAPACITY: usize = 256;
+/// How many past events are kept for the clients resuming a stream.
+const REPLAY_SIZE: usize = 1000;
+/// The channel of the `todos_changed` trigger in todo.sql.
+#[cfg(feature = "postgres")]
+const CHANNEL: &str = "todos_changed";
+#[cfg(feature = "postgres")]
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+#[cfg(feature = "postgres")]
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Created => "created",
+            Action::Updated => "updated",
+            Action::Deleted => "deleted",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TodoEvent {
+    /// Increases with every event of this process. The ids start from the time the process
+    /// started, in microseconds, so they keep increasing across restarts.
+    pub id: u64,
+    pub action: Action,
+    /// The todo after the write, or as it was before its deletion
+    pub todo: Todo,
+}
+
+pub type Events = Arc<EventBus>;
+
+pub struct EventBus {
+    sender: broadcast::Sender<TodoEvent>,
+    history: Mutex<History>,
+}
+
+/// The last events, oldest first.
+struct History {
+    /// The ids of this bus come after this one
+    first_id: u64,
+    last_id: u64,
+    events: VecDeque<TodoEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Events {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        let now = Utc::now();
+        let start = now.timestamp() as u64 * 1_000_000 + u64::from(now.timestamp_subsec_micros());
+        let history = History { first_id: start, last_id: start, events: VecDeque::new() };
+        Arc::new(EventBus { sender, history: Mutex::new(history) })
+    }
+
+    pub async fn publish(&self, action: Action, todo: Todo) {
+        // Sending under the lock keeps the replayed and the live events in the same order
+        let mut history = self.history.lock().await;
+        history.last_id += 1;
+        let event = TodoEvent { id: history.last_id, action, todo };
+        if history.events.len() == REPLAY_SIZE {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+        // Fails only when nobody is subscribed
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TodoEvent> {
+        self.sender.subscribe()
+    }
+
+    /// The events coming after `last_id` which are still kept, then the live ones.
+    /// An id this bus didn't give, from before a restart or from another instance,
+    /// replays nothing. The stream ends when the subscriber lags too far behind, so it can resume.
+    pub async fn subscribe_since(&self, last_id: Option<u64>) -> impl Stream<Item = TodoEvent> + Send {
+        let history = self.history.lock().await;
+        let live = self.sender.subscribe();
+        let missed: Vec<TodoEvent> = match last_id {
+            Some(last_id) if last_id >= history.first_id && last_id <= history.last_id => {
+                history.events.iter().filter(|event| event.id > last_id).cloned().collect()
+            }
+            _ => Vec::new(),
+        };
+        let live = live
+            .take_while(|event| future::ready(event.is_ok()))
+            .filter_map(|event| future::ready(event.ok()));
+        stream::iter(missed).chain(live)
+    }
+}
+
+/// The payload of a `todos_changed` notification.
+#[cfg(feature = "postgres")]
+#[derive(Debug, Deserialize)]
+struct Notification {
+    action: Action,
+    todo: Todo,
+    /// The text was cut to fit in a notification
+    #[serde(default)]
+    truncated: bool,
+}
+
+/// Forward the `todos_changed` notifications to the bus, so the subscribers see the writes
+/// of every instance. The writes made while the database is unreachable are not replayed,
+/// we reconnect with an exponential backoff.
+#[cfg(feature = "postgres")]
+pub async fn listen(url: String, db: Db, events: Events) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        if let Err(e) = forward(&url, &db, &events, &mut backoff).await {
+            eprintln!("lost the {} notifications: {}, reconnecting in {:?}", CHANNEL, e, backoff);
+        }
+        tokio::time::delay_for(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(feature = "postgres")]
+async fn forward(url: &str, db: &Db, events: &EventBus, backoff: &mut Duration) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect(url).await?;
+    listener.listen(CHANNEL).await?;
+    *backoff = MIN_BACKOFF;
+    loop {
+        let notification = listener.recv().await?;
+        let notification: Notification = match serde_json::from_str(notification.payload()) {
+            Ok(notification) => notification,
+            Err(e) => {
+                eprintln!("ignoring a bad {} notification: {}", CHANNEL, e);
+                continue;
+            }
+        };
+        let mut todo = notification.todo;
+        // A deleted todo can't be read back, its subscribers get the cut text
+        if notification.truncated && notification.action != Action::Deleted {
+            match db.get_todo(todo.id, &Scope::Everything).await {
+                Ok(full) => todo = full,
+                // Deleted meanwhile, its own notification follows
+                Err(_) => continue,
+            }
+        }
+        events.publish(notification.action, todo).await;
+    }
+}
+
+/// Make the bus accessible within filter
+pub fn with_events(events: Events) -> impl Filter<Extract = (Events,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || events.clone())
+}
+
+/// A `TodoRepository` publishing the successful writes of another one.
+pub struct PublishingRepository {
+    inner: Db,
+    events: Events,
+}
+
+pub fn publishing(inner: Db, events: Events) -> Db {
+    Arc::new(PublishingRepository { inner, events })
+}
+
+#[async_trait]
+impl TodoRepository for PublishingRepository {
+    async fn get_todo(&self, id: i32, scope: &Scope) -> Result<Todo, TodoError> {
+        self.inner.get_todo(id, scope).await
+    }
+
+    async fn get_todos(&self, ids: &[i32], scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+        self.inner.get_todos(ids, scope).await
+    }
+
+    async fn list_todos(&self, filter: &TodoFilter, offset: i64, limit: i64) -> Result<Page<Todo>, TodoError> {
+        self.inner.list_todos(filter, offset, limit).await
+    }
+
+    async fn list_todos_after(&self, filter: &TodoFilter, after: Option<&Cursor>, limit: i64) -> Result<Vec<Todo>, TodoError> {
+        self.inner.list_todos_after(filter, after, limit).await
+    }
+
+    async fn list_todos_before(&self, filter: &TodoFilter, before: Option<&Cursor>, limit: i64) -> Result<Vec<Todo>, TodoError> {
+        self.inner.list_todos_before(filter, before, limit).await
+    }
+
+    async fn count_todos(&self, filter: &TodoFilter) -> Result<i64, TodoError> {
+        self.inner.count_todos(filter).await
+    }
+
+    async fn create_todo(&self, text: String, completed: bool, actor: &str) -> Result<Todo, TodoError> {
+        let todo = self.inner.create_todo(text, completed, actor).await?;
+        self.events.publish(Action::Created, todo.clone()).await;
+        Ok(todo)
+    }
+
+    async fn update_todo(&self, id: i32, text: String, completed: bool, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let todo = self.inner.update_todo(id, text, completed, version, actor, scope).await?;
+        self.events.publish(Action::Updated, todo.clone()).await;
+        Ok(todo)
+    }
+
+    async fn patch_todo(&self, id: i32, text: Option<String>, completed: Option<bool>, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let todo = self.inner.patch_todo(id, text, completed, version, actor, scope).await?;
+        self.events.publish(Action::Updated, todo.clone()).await;
+        Ok(todo)
+    }
+
+    async fn delete_todo(&self, id: i32, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let todo = self.inner.delete_todo(id, version, actor, scope).await?;
+        self.events.publish(Action::Deleted, todo.clone()).await;
+        Ok(todo)
+    }
+
+    async fn clear_completed(&self, actor: &str, scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+        let todos = self.inner.clear_completed(actor, scope).await?;
+        for todo in &todos {
+            self.events.publish(Action::Deleted, todo.clone()).await;
+        }
+        Ok(todos)
+    }
+
+    // Nothing is published when an all or nothing request is rolled back
+    async fn bulk(&self, operations: Vec<BulkOperation>, atomic: bool, actor: &str, scope: &Scope) -> Result<Vec<Result<Todo, TodoError>>, TodoError> {
+        let actions: Vec<Action> = operations.iter()
+            .map(|operation| match operation {
+                BulkOperation::Create { .. } => Action::Created,
+                BulkOperation::Update { .. } => Action::Updated,
+                BulkOperation::Delete { .. } => Action::Deleted,
+            })
+            .collect();
+        let results = self.inner.bulk(operations, atomic, actor, scope).await?;
+        if !atomic || results.iter().all(Result::is_ok) {
+            for (action, result) in actions.into_iter().zip(&results) {
+                if let Ok(todo) = result {
+                    self.events.publish(action, todo.clone()).await;
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    async fn export_todos(&self, scope: &Scope) -> Result<TodoRows, TodoError> {
+        self.inner.export_todos(scope).await
+    }
+
+    async fn todo_history(&self, id: i32, scope: &Scope) -> Result<Vec<AuditEntry>, TodoError> {
+        self.inner.todo_history(id, scope).await
+    }
+
+    async fn list_audit(&self, opts: &AuditOptions, limit: i64) -> Result<Vec<AuditEntry>, TodoError> {
+        self.inner.list_audit(opts, limit).await
+    }
+
+    async fn list_trash(&self, scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+        self.inner.list_trash(scope).await
+    }
+
+    // Out of the trash, the todo is back for the subscribers
+    async fn restore_todo(&self, id: i32, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let todo = self.inner.restore_todo(id, actor, scope).await?;
+        self.events.publish(Action::Created, todo.clone()).await;
+        Ok(todo)
+    }
+
+    // The subscribers were told when the todos went to the trash
+    async fn purge_trash(&self, deleted_before: DateTime<Utc>) -> Result<u64, TodoError> {
+        self.inner.purge_trash(deleted_before).await
+    }
+
+    async fn create_api_key(&self, prefix: String, salt: String, hash: String, name: String, owner_id: String, scopes: String) -> Result<ApiKey, TodoError> {
+        self.inner.create_api_key(prefix, salt, hash, name, owner_id, scopes).await
+    }
+
+    async fn list_api_keys(&self, owner_id: &str) -> Result<Vec<ApiKey>, TodoError> {
+        self.inner.list_api_keys(owner_id).await
+    }
+
+    async fn revoke_api_key(&self, id: i32, owner_id: &str) -> Result<(), TodoError> {
+        self.inner.revoke_api_key(id, owner_id).await
+    }
+
+    async fn find_api_key(&self, prefix: &str) -> Result<Option<ApiKey>, TodoError> {
+        self.inner.find_api_key(prefix).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn todo(id: i32) -> Todo {
+        Todo { id, text: format!("todo {}", id), completed: false, version: 1, owner_id: "alice".to_string(), deleted_at: None }
+    }
+
+    async fn next_ids(stream: impl Stream<Item = TodoEvent>, n: usize) -> Vec<i32> {
+        stream.take(n).map(|event| event.todo.id).collect().await
+    }
+
+    #[tokio::test]
+    async fn resume_after_an_event_of_this_bus() {
+        let bus = EventBus::new();
+        for id in 1..=3 {
+            bus.publish(Action::Created, todo(id)).await;
+        }
+        let first = bus.history.lock().await.events[0].id;
+        let stream = bus.subscribe_since(Some(first)).await;
+        bus.publish(Action::Created, todo(4)).await;
+        assert_eq!(next_ids(stream, 3).await, vec![2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn unknown_ids_replay_nothing() {
+        let bus = EventBus::new();
+        for id in 1..=3 {
+            bus.publish(Action::Created, todo(id)).await;
+        }
+        // From an instance which started later, or a counter of the old scheme
+        for last_id in &[u64::MAX, 500] {
+            let stream = bus.subscribe_since(Some(*last_id)).await;
+            bus.publish(Action::Updated, todo(1)).await;
+            assert_eq!(next_ids(stream, 1).await, vec![1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn ids_keep_increasing_across_restarts() {
+        let before = EventBus::new();
+        for id in 1..=3 {
+            before.publish(Action::Created, todo(id)).await;
+        }
+        let last = before.history.lock().await.last_id;
+        tokio::time::delay_for(Duration::from_millis(1)).await;
+        let after = EventBus::new();
+        after.publish(Action::Created, todo(4)).await;
+        assert!(after.history.lock().await.last_id > last);
+        // The id of the old process is not replayed against the new one
+        let stream = after.subscribe_since(Some(last)).await;
+        after.publish(Action::Created, todo(5)).await;
+        assert_eq!(next_ids(stream, 1).await, vec![5]);
+    }
+}
@@ -0,0 +1,654 @@
+use super::auth::{self, Auth};
+use super::events::{self, Events};
+use super::handlers;
+use super::models::{AuditOptions, ClearOptions, Db, FormatOptions, ListOptions, UpdateTodo, READ_SCOPE, WRITE_SCOPE};
+use serde::de::DeserializeOwned;
+use warp::Filter;
+
+/// All the TODOs filters combined.
+pub fn rest_todos(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    todos_get(db.clone(), auth.clone())
+        .or(todos_list(db.clone(), auth.clone()))
+        .or(todos_create(db.clone(), auth.clone()))
+        .or(todos_update(db.clone(), auth.clone()))
+        .or(todos_patch(db.clone(), auth.clone()))
+        .or(todos_delete(db.clone(), auth.clone()))
+        .or(todos_bulk(db.clone(), auth.clone()))
+        .or(todos_clear(db.clone(), auth.clone()))
+        .or(todos_export(db.clone(), auth.clone()))
+        .or(todos_import(db.clone(), auth.clone()))
+        .or(todos_trash(db.clone(), auth.clone()))
+        .or(todos_restore(db.clone(), auth.clone()))
+        .or(todos_history(db, auth))
+}
+
+/// GET /todos?offset=3&limit=5 or GET /todos?after=<cursor>&limit=5
+/// both accept &completed=true|false&q=text&sort=id|-id|text|completed
+pub fn todos_list(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos")
+        .and(warp::get())
+        .and(auth::with_scope(auth, READ_SCOPE))
+        .and(warp::query::<ListOptions>())
+        .and(with_db(db))
+        .and_then(handlers::list_todos)
+}
+
+/// GET /todos/:id, with an optional If-None-Match header
+pub fn todos_get(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / i32)
+        .and(warp::get())
+        .and(auth::with_scope(auth, READ_SCOPE))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(with_db(db))
+        .and_then(handlers::get_todo)
+}
+
+/// POST /todos with JSON body, without id
+pub fn todos_create(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos")
+        .and(warp::post())
+        .and(auth::with_scope(auth, WRITE_SCOPE))
+        .and(json_body())
+        .and(with_db(db))
+        .and_then(handlers::create_todos)
+}
+
+/// PUT /todos/:id with JSON BODY
+pub fn todos_update(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / i32)
+        .and(warp::put())
+        .and(auth::with_scope(auth, WRITE_SCOPE))
+        .and(if_match())
+        .and(json_body())
+        .and(with_db(db))
+        .and_then(handlers::update_todo)
+}
+
+/// PATCH /todos/:id with a JSON (or JSON Merge Patch) body holding the fields to change
+pub fn todos_patch(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / i32)
+        .and(warp::patch())
+        .and(auth::with_scope(auth, WRITE_SCOPE))
+        .and(if_match())
+        .and(patch_body())
+        .and(with_db(db))
+        .and_then(handlers::patch_todo)
+}
+
+/// DELETE /todos/:id
+pub fn todos_delete(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / i32)
+        .and(warp::delete())
+        .and(auth::with_scope(auth, WRITE_SCOPE))
+        .and(if_match())
+        .and(with_db(db))
+        .and_then(handlers::delete_todo)
+}
+
+
+/// POST /todos/bulk with a JSON body holding the mode and the operations
+pub fn todos_bulk(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / "bulk")
+        .and(warp::post())
+        .and(auth::with_scope(auth, WRITE_SCOPE))
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json())
+        .and(with_db(db))
+        .and_then(handlers::bulk_todos)
+}
+
+/// DELETE /todos?completed=true
+pub fn todos_clear(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos")
+        .and(warp::delete())
+        .and(auth::with_scope(auth, WRITE_SCOPE))
+        .and(warp::query::<ClearOptions>())
+        .and(with_db(db))
+        .and_then(handlers::clear_todos)
+}
+
+/// GET /todos/export?format=jsonl|csv|md
+pub fn todos_export(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / "export")
+        .and(warp::get())
+        .and(auth::with_scope(auth, READ_SCOPE))
+        .and(warp::query::<FormatOptions>())
+        .and(with_db(db))
+        .and_then(handlers::export_todos)
+}
+
+/// POST /todos/import?format=jsonl|csv|md with the exported document as body
+pub fn todos_import(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / "import")
+        .and(warp::post())
+        .and(auth::with_scope(auth, WRITE_SCOPE))
+        .and(warp::query::<FormatOptions>())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::bytes())
+        .and(with_db(db))
+        .and_then(handlers::import_todos)
+}
+
+/// GET /todos/trash
+pub fn todos_trash(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / "trash")
+        .and(warp::get())
+        .and(auth::with_scope(auth, READ_SCOPE))
+        .and(with_db(db))
+        .and_then(handlers::list_trash)
+}
+
+/// POST /todos/:id/restore
+pub fn todos_restore(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / i32 / "restore")
+        .and(warp::post())
+        .and(auth::with_scope(auth, WRITE_SCOPE))
+        .and(with_db(db))
+        .and_then(handlers::restore_todo)
+}
+
+/// GET /todos/:id/history, oldest first
+pub fn todos_history(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / i32 / "history")
+        .and(warp::get())
+        .and(auth::with_scope(auth, READ_SCOPE))
+        .and(with_db(db))
+        .and_then(handlers::todo_history)
+}
+
+/// GET /todos/events
+pub fn todos_events(events: Events, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("todos" / "events")
+        .and(warp::get())
+        .and(auth::with_scope_from_browser(auth, READ_SCOPE))
+        .and(warp::sse::last_event_id::<u64>())
+        .and(events::with_events(events))
+        .and_then(handlers::todo_events)
+}
+
+/// The API keys filters combined, only admins can manage keys, each one the keys they created.
+pub fn rest_api_keys(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    api_keys_list(db.clone(), auth.clone())
+        .or(api_keys_create(db.clone(), auth.clone()))
+        .or(api_keys_revoke(db, auth))
+}
+
+/// GET /api-keys, the keys of the caller
+pub fn api_keys_list(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api-keys")
+        .and(warp::get())
+        .and(auth::with_role(auth, auth::ADMIN_ROLE))
+        .and(with_db(db))
+        .and_then(handlers::list_api_keys)
+}
+
+/// POST /api-keys with JSON body, the key is only shown in the response
+pub fn api_keys_create(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api-keys")
+        .and(warp::post())
+        .and(auth::with_role(auth, auth::ADMIN_ROLE))
+        .and(json_body())
+        .and(with_db(db))
+        .and_then(handlers::create_api_key)
+}
+
+/// DELETE /api-keys/:id revokes the key, 404 when it belongs to someone else
+pub fn api_keys_revoke(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api-keys" / i32)
+        .and(warp::delete())
+        .and(auth::with_role(auth, auth::ADMIN_ROLE))
+        .and(with_db(db))
+        .and_then(handlers::revoke_api_key)
+}
+
+/// GET /audit?actor=alice&todo_id=3&since=2020-06-01T00:00:00Z&until=...&before=<id>&limit=50
+/// the writes of every todo newest first, admin only
+pub fn audit_list(db: Db, auth: Auth) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("audit")
+        .and(warp::get())
+        .and(auth::with_role(auth, auth::ADMIN_ROLE))
+        .and(warp::query::<AuditOptions>())
+        .and(with_db(db))
+        .and_then(handlers::list_audit)
+}
+
+/// Make the db accessible within filter
+pub fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || db.clone())
+}
+
+/// The optional If-Match header of the writes
+fn if_match() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("if-match")
+}
+
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract= (T,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+}
+
+/// The body is parsed by hand, `warp::body::json()` only knows about `application/json`
+fn patch_body() -> impl Filter<Extract= (UpdateTodo,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 16)
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and_then(handlers::parse_patch)
+}
+
+// The REST API over the in-memory storage, as the server runs with `TODO_STORAGE=memory`
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::auth::{AuthConfig, Claims, ADMIN_ROLE};
+    use super::super::events::EventBus;
+    use super::super::gql::{schema, Context};
+    use super::super::handlers::handle_rejection;
+    use super::super::models::MemoryTodoRepository;
+    use bytes::Bytes;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use juniper::http::GraphQLRequest;
+    use serde_json::{json, Value};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use warp::http::{Response, StatusCode};
+    use warp::test::request;
+
+    const SECRET: &str = "test-secret";
+
+    fn api() -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone + 'static {
+        api_over(Arc::new(MemoryTodoRepository::new()))
+    }
+
+    fn api_over(db: Db) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone + 'static {
+        let auth = AuthConfig::hs256(SECRET, db.clone());
+        rest_todos(db.clone(), auth.clone())
+            .or(rest_api_keys(db, auth))
+            .recover(handle_rejection)
+    }
+
+    fn bearer(sub: &str, roles: &[&str]) -> String {
+        let claims = Claims {
+            sub: sub.to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            exp: 4_000_000_000,
+            scopes: None,
+        };
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap();
+        format!("Bearer {}", token)
+    }
+
+    fn body(res: &Response<Bytes>) -> Value {
+        serde_json::from_slice(res.body()).unwrap()
+    }
+
+    // The GraphQL API over the same storage, on behalf of `sub`
+    async fn graphql(db: &Db, sub: &str, query: &str) -> Value {
+        let claims = Claims { sub: sub.to_string(), roles: Vec::new(), exp: 0, scopes: None };
+        let context = Context::new(db.clone(), Some(claims), EventBus::new());
+        let response = GraphQLRequest::new(query.to_string(), None, None).execute(&schema(), &context).await;
+        serde_json::to_value(&response).unwrap()
+    }
+
+    async fn create<F>(api: &F, token: &str, text: &str) -> Value
+    where
+        F: Filter + 'static,
+        F::Extract: warp::Reply + Send,
+    {
+        let res = request().method("POST").path("/todos")
+            .header("authorization", token)
+            .json(&json!({ "text": text }))
+            .reply(api).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        body(&res)
+    }
+
+    // A new API key of the admin behind `token`, its id and the key given to the client
+    async fn create_api_key<F>(api: &F, token: &str, scopes: &[&str]) -> (i64, String)
+    where
+        F: Filter + 'static,
+        F::Extract: warp::Reply + Send,
+    {
+        let res = request().method("POST").path("/api-keys")
+            .header("authorization", token)
+            .json(&json!({ "name": "ci", "scopes": scopes }))
+            .reply(api).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let created = body(&res);
+        (created["id"].as_i64().unwrap(), created["key"].as_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn create_and_get() {
+        let api = api();
+        let alice = bearer("alice", &[]);
+        let res = request().method("POST").path("/todos")
+            .header("authorization", &alice)
+            .json(&json!({ "text": "milk" }))
+            .reply(&api).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()["location"], "/todos/1");
+        let todo = body(&res);
+        assert_eq!(todo["id"], 1);
+        assert_eq!(todo["owner_id"], "alice");
+        assert_eq!(todo["completed"], false);
+
+        let res = request().path("/todos/1").header("authorization", &alice).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res)["text"], "milk");
+        let etag = res.headers()["etag"].to_str().unwrap().to_string();
+        let res = request().path("/todos/1")
+            .header("authorization", &alice)
+            .header("if-none-match", &etag)
+            .reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn read_only_api_keys_cannot_write() {
+        let api = api();
+        let alice = bearer("alice", &[ADMIN_ROLE]);
+        create(&api, &alice, "milk").await;
+        let (_, key) = create_api_key(&api, &alice, &[READ_SCOPE]).await;
+        let res = request().path("/todos/1").header("x-api-key", &key).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request().method("POST").path("/todos")
+            .header("x-api-key", &key)
+            .json(&json!({ "text": "eggs" }))
+            .reply(&api).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = request().method("DELETE").path("/todos/1")
+            .header("authorization", format!("ApiKey {}", key))
+            .reply(&api).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = request().path("/todos").header("authorization", &alice).reply(&api).await;
+        assert_eq!(body(&res)["total"], 1);
+    }
+
+    #[tokio::test]
+    async fn admins_manage_their_own_api_keys() {
+        let api = api();
+        let (alice, bob) = (bearer("alice", &[ADMIN_ROLE]), bearer("bob", &[ADMIN_ROLE]));
+        let (id, key) = create_api_key(&api, &alice, &[READ_SCOPE, WRITE_SCOPE]).await;
+        let res = request().path("/api-keys").header("authorization", &bob).reply(&api).await;
+        assert_eq!(body(&res), json!([]));
+        let res = request().path("/api-keys").header("authorization", &alice).reply(&api).await;
+        assert_eq!(body(&res)[0]["id"], id);
+
+        let res = request().method("DELETE").path(&format!("/api-keys/{}", id)).header("authorization", &bob).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = request().path("/todos").header("x-api-key", &key).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request().method("DELETE").path(&format!("/api-keys/{}", id)).header("authorization", &alice).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = request().path("/todos").header("x-api-key", &key).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = request().path("/api-keys").header("authorization", bearer("carol", &[])).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn callers_only_see_their_todos() {
+        let api = api();
+        let (alice, bob) = (bearer("alice", &[]), bearer("bob", &[]));
+        let res = request().path("/todos").reply(&api).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        create(&api, &alice, "milk").await;
+        create(&api, &bob, "bread").await;
+        let res = request().path("/todos").header("authorization", &bob).reply(&api).await;
+        let page = body(&res);
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["items"][0]["text"], "bread");
+        let res = request().path("/todos/1").header("authorization", &bob).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = request().method("DELETE").path("/todos/1").header("authorization", &bob).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = request().path("/todos").header("authorization", bearer("root", &[ADMIN_ROLE])).reply(&api).await;
+        assert_eq!(body(&res)["total"], 2);
+    }
+
+    #[tokio::test]
+    async fn stale_writes_are_refused() {
+        let api = api();
+        let alice = bearer("alice", &[]);
+        create(&api, &alice, "milk").await;
+        let res = request().path("/todos/1").header("authorization", &alice).reply(&api).await;
+        let first_etag = res.headers()["etag"].to_str().unwrap().to_string();
+
+        let res = request().method("PATCH").path("/todos/1")
+            .header("authorization", &alice)
+            .json(&json!({ "completed": true, "version": 1 }))
+            .reply(&api).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res)["version"], 2);
+
+        let res = request().method("PATCH").path("/todos/1")
+            .header("authorization", &alice)
+            .json(&json!({ "completed": false, "version": 1 }))
+            .reply(&api).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = request().method("PUT").path("/todos/1")
+            .header("authorization", &alice)
+            .header("if-match", &first_etag)
+            .json(&json!({ "text": "bread", "completed": false }))
+            .reply(&api).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        // The body version is current, the If-Match is not
+        let res = request().method("PATCH").path("/todos/1")
+            .header("authorization", &alice)
+            .header("if-match", &first_etag)
+            .json(&json!({ "text": "bread", "version": 2 }))
+            .reply(&api).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn trash_restore_and_history() {
+        let api = api();
+        let alice = bearer("alice", &[]);
+        create(&api, &alice, "milk").await;
+        let res = request().method("DELETE").path("/todos/1").header("authorization", &alice).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = request().path("/todos/1").header("authorization", &alice).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = request().path("/todos/trash").header("authorization", &alice).reply(&api).await;
+        assert_eq!(body(&res)[0]["id"], 1);
+
+        let res = request().method("POST").path("/todos/1/restore").header("authorization", &alice).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res)["version"], 3);
+
+        let res = request().path("/todos/1/history").header("authorization", &alice).reply(&api).await;
+        let history = body(&res);
+        let actions: Vec<&str> = history.as_array().unwrap().iter().map(|entry| entry["action"].as_str().unwrap()).collect();
+        assert_eq!(actions, vec!["created", "deleted", "restored"]);
+        assert_eq!(history[0]["actor"], "alice");
+        assert_eq!(history[0]["before"], Value::Null);
+        assert_eq!(history[1]["after"]["version"], 2);
+    }
+
+    #[tokio::test]
+    async fn trashed_todos_are_hidden() {
+        let api = api();
+        let alice = bearer("alice", &[]);
+        create(&api, &alice, "milk").await;
+        create(&api, &alice, "eggs").await;
+        let res = request().method("DELETE").path("/todos/1").header("authorization", &alice).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        for path in &["/todos", "/todos?after=", "/todos?completed=false&sort=-id"] {
+            let res = request().path(path).header("authorization", &alice).reply(&api).await;
+            let items = body(&res)["items"].clone();
+            assert_eq!(items.as_array().unwrap().len(), 1, "{}", path);
+            assert_eq!(items[0]["text"], "eggs", "{}", path);
+        }
+        let res = request().path("/todos?q=milk").header("authorization", &alice).reply(&api).await;
+        assert_eq!(body(&res)["total"], 0);
+        let res = request().path("/todos/1").header("authorization", &alice).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn pages_have_links() {
+        let api = api();
+        let alice = bearer("alice", &[]);
+        for text in &["a", "b", "c"] {
+            create(&api, &alice, text).await;
+        }
+        let res = request().path("/todos?limit=2").header("authorization", &alice).reply(&api).await;
+        let page = body(&res);
+        assert_eq!(page["total"], 3);
+        assert_eq!(page["items"].as_array().unwrap().len(), 2);
+        let link = res.headers()["link"].to_str().unwrap();
+        assert!(link.contains("offset=2"), "{}", link);
+        assert!(link.contains("rel=\"next\""), "{}", link);
+    }
+
+    #[tokio::test]
+    async fn bad_cursors_are_refused() {
+        let api = api();
+        let alice = bearer("alice", &[]);
+        for text in &["a", "b", "c"] {
+            create(&api, &alice, text).await;
+        }
+        let res = request().path("/todos?after=&limit=1").header("authorization", &alice).reply(&api).await;
+        let next = body(&res)["next"].as_str().unwrap().to_string();
+        let res = request().path(&format!("/todos?after={}&limit=1", next)).header("authorization", &alice).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(&res)["items"][0]["text"], "b");
+
+        let mut tampered: Value = serde_json::from_slice(&base64::decode_config(&next, base64::URL_SAFE_NO_PAD).unwrap()).unwrap();
+        tampered["id"] = json!(-1);
+        let tampered = base64::encode_config(tampered.to_string(), base64::URL_SAFE_NO_PAD);
+        let not_json = base64::encode_config("milk", base64::URL_SAFE_NO_PAD);
+        let paths = vec![
+            format!("/todos?after={}", tampered),
+            format!("/todos?after={}", not_json),
+            "/todos?after=%25%25garbage".to_string(),
+            // A cursor of the id order used with another order
+            format!("/todos?after={}&sort=text", next),
+        ];
+        for path in paths {
+            let res = request().path(&path).header("authorization", &alice).reply(&api).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", path);
+            assert!(body(&res)["message"].as_str().unwrap().starts_with("INVALID_CURSOR"), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn bad_list_options_are_refused() {
+        let db: Db = Arc::new(MemoryTodoRepository::new());
+        let api = api_over(db.clone());
+        let alice = bearer("alice", &[]);
+        for path in &["/todos?sort=name", "/todos?after=&sort=name"] {
+            let res = request().path(path).header("authorization", &alice).reply(&api).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", path);
+            assert_eq!(body(&res)["message"], "VALIDATION: unknown sort order name, expected id, -id, text or completed", "{}", path);
+        }
+        let res = request().path("/todos?completed=maybe").header("authorization", &alice).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let response = graphql(&db, "alice", r#"{ todosList(opt: { sort: "name" }) { id } }"#).await;
+        assert_eq!(response["errors"][0]["message"], "VALIDATION: unknown sort order name, expected id, -id, text or completed");
+        let response = graphql(&db, "alice", r#"{ todosList(opt: { completed: "maybe" }) { id } }"#).await;
+        assert!(response["data"].is_null());
+        assert_eq!(response["errors"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rest_and_graphql_list_the_same_todos() {
+        let db: Db = Arc::new(MemoryTodoRepository::new());
+        let api = api_over(db.clone());
+        let (alice, bob) = (bearer("alice", &[]), bearer("bob", &[]));
+        for (text, completed) in &[("milk", false), ("eggs", true), ("oat milk", true), ("bread", false)] {
+            let res = request().method("POST").path("/todos")
+                .header("authorization", &alice)
+                .json(&json!({ "text": text, "completed": completed }))
+                .reply(&api).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        create(&api, &bob, "bob's milk").await;
+
+        let filters = [
+            ("", "{}"),
+            ("completed=true", "{ completed: true }"),
+            ("q=milk", r#"{ q: "milk" }"#),
+            ("sort=-id&limit=2", r#"{ sort: "-id", limit: 2 }"#),
+            ("sort=text&completed=false", r#"{ sort: "text", completed: false }"#),
+            ("sort=completed&offset=1", r#"{ sort: "completed", offset: 1 }"#),
+            ("after=&sort=text&limit=3", r#"{ after: "", sort: "text", limit: 3 }"#),
+        ];
+        for (params, opt) in filters.iter() {
+            let res = request().path(&format!("/todos?{}", params)).header("authorization", &alice).reply(&api).await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", params);
+            let rest: Vec<Value> = body(&res)["items"].as_array().unwrap().iter().map(|todo| todo["id"].clone()).collect();
+            let response = graphql(&db, "alice", &format!("{{ todosList(opt: {}) {{ databaseId }} }}", opt)).await;
+            let graphql: Vec<Value> = response["data"]["todosList"].as_array().unwrap().iter().map(|todo| todo["databaseId"].clone()).collect();
+            assert!(!rest.is_empty(), "{}", params);
+            assert_eq!(rest, graphql, "{}", params);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_bulk_writes_nothing() {
+        let api = api();
+        let alice = bearer("alice", &[]);
+        let res = request().method("POST").path("/todos/bulk")
+            .header("authorization", &alice)
+            .json(&json!({ "operations": [{ "op": "create", "text": "milk" }, { "op": "delete", "id": 99 }] }))
+            .reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let response = body(&res);
+        assert_eq!(response["committed"], false);
+        assert_eq!(response["results"][0]["status"], 424);
+        let res = request().path("/todos").header("authorization", &alice).reply(&api).await;
+        assert_eq!(body(&res)["total"], 0);
+    }
+
+    #[tokio::test]
+    async fn best_effort_bulk_keeps_the_good_operations() {
+        let api = api();
+        let alice = bearer("alice", &[]);
+        let res = request().method("POST").path("/todos/bulk")
+            .header("authorization", &alice)
+            .json(&json!({
+                "mode": "best_effort",
+                "operations": [{ "op": "create", "text": "milk" }, { "op": "delete", "id": 99 }, { "op": "create", "text": "eggs" }],
+            }))
+            .reply(&api).await;
+        assert_eq!(res.status(), StatusCode::MULTI_STATUS);
+        let response = body(&res);
+        assert_eq!(response["mode"], "best_effort");
+        assert_eq!(response["committed"], true);
+        let statuses: Vec<u64> = response["results"].as_array().unwrap().iter()
+            .map(|result| result["status"].as_u64().unwrap())
+            .collect();
+        assert_eq!(statuses, vec![201, 404, 201]);
+        assert_eq!(response["results"][1]["index"], 1);
+        assert!(response["results"][1]["error"].as_str().unwrap().starts_with("NOT_FOUND"));
+        let res = request().path("/todos").header("authorization", &alice).reply(&api).await;
+        let page = body(&res);
+        assert_eq!(page["total"], 2);
+        assert_eq!(page["items"][0]["text"], "milk");
+        assert_eq!(page["items"][1]["text"], "eggs");
+    }
+
+    #[tokio::test]
+    async fn warp_rejections_are_json_errors() {
+        let api = api();
+        let alice = bearer("alice", &[]);
+        let res = request().method("POST").path("/todos")
+            .header("authorization", &alice)
+            .header("content-type", "text/plain")
+            .body("{\"text\":\"milk\"}")
+            .reply(&api).await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(body(&res)["message"], "UNSUPPORTED_MEDIA_TYPE");
+
+        let res = request().path("/todos?limit=many").header("authorization", &alice).reply(&api).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
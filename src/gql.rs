@@ -0,0 +1,740 @@
+use futures::{future, stream, Stream, StreamExt};
+use juniper::parser::parse_document_source;
+use juniper::{DefaultScalarValue, Definition, Document, FieldError, InputValue, IntoFieldError, Operation, OperationType, Selection, ID};
+use std::collections::HashMap;
+use std::pin::Pin;
+use tokio::sync::Mutex;
+use super::auth::{AuthError, Claims};
+use super::events::{Action, Events};
+use super::models::{AuditEntry, Db, ListOptions, NewTodo, Scope, Todo, TodoError, UpdateTodo, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE, READ_SCOPE, WRITE_SCOPE};
+use super::models::{db_list_todos, db_list_todos_after, db_list_todos_window, db_count_todos, db_get_todo, db_get_todos, db_create_todos, db_patch_todo, db_delete_todo, db_clear_completed, db_todo_history};
+use chrono::{DateTime, Utc};
+
+pub struct Context {
+    pub pool: Db,
+    /// The caller, `None` when the request carries no token
+    pub claims: Option<Claims>,
+    pub events: Events,
+    pub loader: TodoLoader,
+}
+
+impl Context {
+    pub fn new(pool: Db, claims: Option<Claims>, events: Events) -> Self {
+        Context { pool, claims, events, loader: TodoLoader::default() }
+    }
+
+    /// The caller, who must have sent a valid token.
+    pub fn claims(&self) -> Result<&Claims, AuthError> {
+        self.claims.as_ref().ok_or(AuthError::MissingToken)
+    }
+
+    /// The caller, whose token or API key must grant `scope`.
+    pub fn claims_with(&self, scope: &'static str) -> Result<&Claims, AuthError> {
+        let claims = self.claims()?;
+        claims.require_scope(scope)?;
+        Ok(claims)
+    }
+
+    /// Load the todos of `ids` with a single query, before the fields asking for them run.
+    pub async fn prefetch(&self, ids: &[i32]) {
+        if let Ok(claims) = self.claims_with(READ_SCOPE) {
+            // The fields load their todo again and report the error
+            let _ = self.loader.load_many(ids, &claims.scope(), &self.pool).await;
+        }
+    }
+}
+
+impl juniper::Context for Context {}
+
+/// The todos of the `todo` and `node` fields of a request. The ids the document asks for
+/// are collected by `requested_todos` and loaded with a single query before it runs, the
+/// others one at a time. The todos are then kept until the end of the request, which has
+/// a single caller.
+#[derive(Default)]
+pub struct TodoLoader {
+    /// `None` when the todo doesn't exist or is not visible
+    loaded: Mutex<HashMap<i32, Option<Todo>>>,
+}
+
+impl TodoLoader {
+    pub async fn load(&self, id: i32, scope: &Scope, db: &Db) -> Result<Option<Todo>, TodoError> {
+        self.load_many(&[id], scope, db).await?;
+        Ok(self.loaded.lock().await.get(&id).cloned().flatten())
+    }
+
+    /// Load the todos of `ids` which are not loaded yet. The lock is held during the query,
+    /// so the fields running concurrently wait for it instead of loading the same todos.
+    async fn load_many(&self, ids: &[i32], scope: &Scope, db: &Db) -> Result<(), TodoError> {
+        let mut loaded = self.loaded.lock().await;
+        let mut missing: Vec<i32> = ids.iter().copied().filter(|id| !loaded.contains_key(id)).collect();
+        missing.sort_unstable();
+        missing.dedup();
+        if !missing.is_empty() {
+            let todos = db_get_todos(&missing, scope, db).await?;
+            for id in missing {
+                loaded.insert(id, None);
+            }
+            for todo in todos {
+                loaded.insert(todo.id, Some(todo));
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep what a mutation wrote, the next fields must not see the old todo.
+    pub async fn prime(&self, id: i32, todo: Option<Todo>) {
+        self.loaded.lock().await.insert(id, todo);
+    }
+}
+
+/// The operation `operation_name` of a document, or its only operation.
+pub fn operation<'a, 'd>(document: &'d Document<'a, DefaultScalarValue>, operation_name: Option<&str>) -> Option<&'d Operation<'a, DefaultScalarValue>> {
+    let mut operations = document.iter().filter_map(|definition| match definition {
+        Definition::Operation(operation) => Some(&operation.item),
+        Definition::Fragment(_) => None,
+    });
+    match operation_name {
+        Some(name) => operations.find(|operation| operation.name.as_ref().map(|name| name.item) == Some(name)),
+        None => match (operations.next(), operations.next()) {
+            (Some(operation), None) => Some(operation),
+            _ => None,
+        },
+    }
+}
+
+/// The values of the variables of an operation, the defaults of its document
+/// for the ones the request leaves out.
+pub fn variable_values<'a>(operation: &'a Operation<'a, DefaultScalarValue>, variables: Option<&'a InputValue>) -> HashMap<&'a str, &'a InputValue> {
+    let mut values = HashMap::new();
+    if let Some(definitions) = &operation.variable_definitions {
+        for (name, definition) in &definitions.item.items {
+            if let Some(default) = &definition.default_value {
+                values.insert(name.item, &default.item);
+            }
+        }
+    }
+    if let Some(InputValue::Object(variables)) = variables {
+        for (name, value) in variables {
+            values.insert(name.item.as_str(), &value.item);
+        }
+    }
+    values
+}
+
+/// The ids of the todos which the `todo` and `node` fields of a query will load, as far as
+/// they are known before it runs: written in the document, or given by the variables.
+pub fn requested_todos(schema: &Schema, query: &str, operation_name: Option<&str>, variables: Option<&InputValue>) -> Vec<i32> {
+    let document = match parse_document_source(query, &schema.schema) {
+        Ok(document) => document,
+        Err(_) => return Vec::new(),
+    };
+    let operation = match operation(&document, operation_name) {
+        Some(operation) if operation.operation_type == OperationType::Query => operation,
+        _ => return Vec::new(),
+    };
+    let values = variable_values(operation, variables);
+    let fragments: HashMap<&str, &Vec<Selection<DefaultScalarValue>>> = document.iter()
+        .filter_map(|definition| match definition {
+            Definition::Fragment(fragment) => Some((fragment.item.name.item, &fragment.item.selection_set)),
+            Definition::Operation(_) => None,
+        })
+        .collect();
+    let mut ids = Vec::new();
+    let mut spread = Vec::new();
+    root_todos(&operation.selection_set, &fragments, &values, &mut spread, &mut ids);
+    ids
+}
+
+// The `todo` and `node` fields are on the query root, maybe inside fragments
+fn root_todos<'a>(selections: &'a [Selection<'a, DefaultScalarValue>], fragments: &HashMap<&'a str, &'a Vec<Selection<'a, DefaultScalarValue>>>, variables: &HashMap<&str, &InputValue>, spread: &mut Vec<&'a str>, ids: &mut Vec<i32>) {
+    for selection in selections {
+        match selection {
+            Selection::Field(field) => {
+                let field = &field.item;
+                let argument = field.arguments.as_ref()
+                    .and_then(|arguments| arguments.item.items.iter().find(|(name, _)| name.item == "id"))
+                    .map(|(_, value)| match &value.item {
+                        InputValue::Variable(name) => variables.get(name.as_str()).copied(),
+                        value => Some(value),
+                    });
+                let id = match (field.name.item, argument.flatten()) {
+                    ("todo", Some(InputValue::Scalar(DefaultScalarValue::Int(id)))) => Some(*id),
+                    ("todo", Some(InputValue::Scalar(DefaultScalarValue::String(id)))) => todo_id(&ID::new(id.clone())),
+                    ("node", Some(InputValue::Scalar(DefaultScalarValue::String(id)))) => match from_global_id(&ID::new(id.clone())) {
+                        Some((typename, id)) if typename == "Todo" => Some(id),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                ids.extend(id);
+            }
+            Selection::InlineFragment(fragment) => root_todos(&fragment.item.selection_set, fragments, variables, spread, ids),
+            Selection::FragmentSpread(fragment) => {
+                let name = fragment.item.name.item;
+                // Each fragment once, a cyclic one is refused by juniper
+                if let Some(selections) = fragments.get(name).copied().filter(|_| !spread.contains(&name)) {
+                    spread.push(name);
+                    root_todos(selections, fragments, variables, spread, ids);
+                }
+            }
+        }
+    }
+}
+
+/// The errors of the resolvers, the code and the HTTP status the REST API would
+/// answer are in the extensions so clients can tell them apart.
+#[derive(Debug)]
+pub enum GqlError {
+    Auth(AuthError),
+    Todo(TodoError),
+}
+
+impl From<AuthError> for GqlError {
+    fn from(e: AuthError) -> Self {
+        GqlError::Auth(e)
+    }
+}
+
+impl From<TodoError> for GqlError {
+    fn from(e: TodoError) -> Self {
+        GqlError::Todo(e)
+    }
+}
+
+impl juniper::IntoFieldError for GqlError {
+    fn into_field_error(self) -> FieldError {
+        let (message, status) = match &self {
+            GqlError::Auth(e) => (e.to_string(), e.status()),
+            GqlError::Todo(e) => (e.to_string(), e.status()),
+        };
+        // Every message starts with its code, as in `NOT_FOUND: no todo with id 3`
+        let code = message.split(':').next().unwrap_or_default().to_string();
+        let mut extensions = juniper::Object::with_capacity(5);
+        extensions.add_field("code", juniper::Value::scalar(code));
+        extensions.add_field("status", juniper::Value::scalar(status.as_u16() as i32));
+        if let GqlError::Todo(TodoError::StaleVersion { id, expected, current }) = self {
+            extensions.add_field("id", juniper::Value::scalar(id));
+            extensions.add_field("expectedVersion", juniper::Value::scalar(expected));
+            extensions.add_field("currentVersion", juniper::Value::scalar(current));
+        }
+        FieldError::new(message, juniper::Value::object(extensions))
+    }
+}
+
+/// The Relay global id of an object: its type and its database id, in base64.
+pub fn global_id(typename: &str, id: i32) -> ID {
+    ID::new(base64::encode(format!("{}:{}", typename, id)))
+}
+
+/// The type and the database id of a global id, `None` if it is not one of ours.
+pub fn from_global_id(id: &ID) -> Option<(String, i32)> {
+    let decoded = String::from_utf8(base64::decode(id.to_string()).ok()?).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    let typename = parts.next()?.to_string();
+    let id = parts.next()?.parse().ok()?;
+    Some((typename, id))
+}
+
+/// The database id of a todo, from its global id or from the database id itself.
+pub fn todo_id(id: &ID) -> Option<i32> {
+    match from_global_id(id) {
+        Some((typename, id)) if typename == "Todo" => Some(id),
+        Some(_) => None,
+        None => id.parse().ok(),
+    }
+}
+
+// The mutations refuse an id which is not the one of a todo
+fn required_todo_id(id: &ID) -> Result<i32, GqlError> {
+    todo_id(id).ok_or_else(|| GqlError::Todo(TodoError::Validation(format!("{} is not the id of a todo", id.as_str()))))
+}
+
+// `id` is the global id of the Node interface, the id of the REST API is `databaseId`
+#[juniper::graphql_object(Context = Context, interfaces = [&Node],)]
+impl Todo {
+    fn id(&self) -> ID {
+        global_id("Todo", self.id)
+    }
+
+    fn databaseId(&self) -> i32 {
+        self.id
+    }
+
+    fn text(&self) -> &str {
+        &self.text
+    }
+
+    fn completed(&self) -> bool {
+        self.completed
+    }
+
+    fn version(&self) -> i32 {
+        self.version
+    }
+
+    fn ownerId(&self) -> &str {
+        &self.owner_id
+    }
+
+    // The last writes of the todo, oldest first, as in GET /todos/:id/history.
+    // `last` of them, 20 by default and 100 at most, so the cost limits can count them
+    async fn history(&self, context: &Context, last: Option<i32>) -> Result<Vec<AuditEntry>, GqlError> {
+        let scope = context.claims_with(READ_SCOPE)?.scope();
+        let mut history = db_todo_history(self.id, &scope, &context.pool).await?;
+        let last = last.map_or(DEFAULT_PAGE_SIZE, i64::from).max(0).min(MAX_PAGE_SIZE) as usize;
+        history.drain(..history.len().saturating_sub(last));
+        Ok(history)
+    }
+}
+
+// The snapshots are the todos as JSON, like the REST API returns them
+#[juniper::graphql_object(Context = Context,)]
+impl AuditEntry {
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn todoId(&self) -> i32 {
+        self.todo_id
+    }
+
+    fn actor(&self) -> &str {
+        &self.actor
+    }
+
+    /// One of created, updated, deleted, restored or purged
+    fn action(&self) -> &str {
+        &self.action
+    }
+
+    fn before(&self) -> Option<String> {
+        self.before.as_ref().map(|before| before.to_string())
+    }
+
+    fn after(&self) -> Option<String> {
+        self.after.as_ref().map(|after| after.to_string())
+    }
+
+    fn createdAt(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+}
+
+/// The objects a Relay client can refetch with `node(id:)`.
+pub enum Node {
+    Todo(Todo),
+}
+
+juniper::graphql_interface!(Node: Context |&self| {
+    field id() -> ID {
+        match self {
+            Node::Todo(todo) => global_id("Todo", todo.id),
+        }
+    }
+
+    instance_resolvers: |_| {
+        &Todo => match self { Node::Todo(todo) => Some(todo) },
+    }
+});
+
+#[derive(juniper::GraphQLObject)]
+pub struct PageInfo {
+    pub has_next_page: bool,
+    pub has_previous_page: bool,
+    pub start_cursor: Option<String>,
+    pub end_cursor: Option<String>,
+}
+
+#[derive(juniper::GraphQLObject)]
+#[graphql(Context = Context)]
+pub struct TodoEdge {
+    pub cursor: String,
+    pub node: Todo,
+}
+
+/// A page of the `todos` field, the total is only counted when it is asked for.
+pub struct TodoConnection {
+    edges: Vec<TodoEdge>,
+    page_info: PageInfo,
+    opts: ListOptions,
+    scope: Scope,
+}
+
+#[juniper::graphql_object(Context = Context,)]
+impl TodoConnection {
+    fn edges(&self) -> &[TodoEdge] {
+        &self.edges
+    }
+
+    fn pageInfo(&self) -> &PageInfo {
+        &self.page_info
+    }
+
+    // Every todo matching the filter, not only the ones of this page
+    async fn totalCount(&self, context: &Context) -> Result<i32, GqlError> {
+        let total = db_count_todos(&self.opts, &self.scope, &context.pool).await?;
+        Ok(total as i32)
+    }
+}
+
+/// The filters and the order of the `todos` field, as in GET /todos.
+#[derive(Debug, Default, juniper::GraphQLInputObject)]
+pub struct TodoFilterInput {
+    pub completed: Option<bool>,
+    pub q: Option<String>,
+    /// One of id, -id, text or completed
+    pub sort: Option<String>,
+}
+
+pub struct Query;
+
+#[juniper::graphql_object(Context = Context,)]
+impl Query {
+    fn apiVersion() -> &str {
+        "1.0"
+    }
+
+    // Null when the todo doesn't exist or belongs to someone else, the id is its global id or its databaseId
+    async fn todo(context: &Context, id: ID) -> Result<Option<Todo>, GqlError> {
+        let scope = context.claims_with(READ_SCOPE)?.scope();
+        let todo = match todo_id(&id) {
+            Some(id) => context.loader.load(id, &scope, &context.pool).await?,
+            None => None,
+        };
+        Ok(todo)
+    }
+
+    // The Relay connection of the todos, `first` and `after` page forward, `last` and `before` backward
+    async fn todos(context: &Context, first: Option<i32>, after: Option<String>, last: Option<i32>, before: Option<String>, filter: Option<TodoFilterInput>) -> Result<TodoConnection, GqlError> {
+        let scope = context.claims_with(READ_SCOPE)?.scope();
+        let filter = filter.unwrap_or_default();
+        let opts = ListOptions { completed: filter.completed, q: filter.q, sort: filter.sort, ..ListOptions::default() };
+        let window = db_list_todos_window(&opts, first, after.as_deref(), last, before.as_deref(), &scope, &context.pool).await?;
+        let edges: Vec<TodoEdge> = window.edges.into_iter()
+            .map(|(cursor, node)| TodoEdge { cursor, node })
+            .collect();
+        let page_info = PageInfo {
+            has_next_page: window.has_next,
+            has_previous_page: window.has_previous,
+            start_cursor: edges.first().map(|edge| edge.cursor.clone()),
+            end_cursor: edges.last().map(|edge| edge.cursor.clone()),
+        };
+        Ok(TodoConnection { edges, page_info, opts, scope })
+    }
+
+    // Null when the id is unknown, malformed or not visible to the caller
+    async fn node(context: &Context, id: ID) -> Result<Option<Node>, GqlError> {
+        let scope = context.claims_with(READ_SCOPE)?.scope();
+        let node = match from_global_id(&id) {
+            Some((typename, id)) if typename == "Todo" => {
+                context.loader.load(id, &scope, &context.pool).await?.map(Node::Todo)
+            }
+            _ => None,
+        };
+        Ok(node)
+    }
+
+    // Same options, same validation and same visibility as GET /todos
+    async fn todosList(context: &Context, opt: ListOptions) -> Result<Vec<Todo>, GqlError> {
+        let scope = context.claims_with(READ_SCOPE)?.scope();
+        let res = if opt.after.is_some() {
+            db_list_todos_after(&opt, &scope, &context.pool).await?.items
+        } else {
+            db_list_todos(&opt, &scope, &context.pool).await?.items
+        };
+        Ok(res)
+    }
+}
+
+pub struct Mutation;
+
+// Same rules as the REST routes: the todos of the caller only, unless admin,
+// and a stale `version` fails with STALE_VERSION. The todos are given by their
+// global id or their databaseId.
+#[juniper::graphql_object(Context = Context,)]
+impl Mutation {
+    async fn createTodo(context: &Context, input: NewTodo) -> Result<Todo, GqlError> {
+        let claims = context.claims_with(WRITE_SCOPE)?;
+        let todo = db_create_todos(input.text, input.completed.unwrap_or(false), &claims.sub, &context.pool).await?;
+        context.loader.prime(todo.id, Some(todo.clone())).await;
+        Ok(todo)
+    }
+
+    async fn updateTodo(context: &Context, id: ID, patch: UpdateTodo) -> Result<Todo, GqlError> {
+        let claims = context.claims_with(WRITE_SCOPE)?;
+        let id = required_todo_id(&id)?;
+        let todo = db_patch_todo(id, patch, &claims.sub, &claims.scope(), &context.pool).await?;
+        context.loader.prime(id, Some(todo.clone())).await;
+        Ok(todo)
+    }
+
+    // The version read is the one written, so a concurrent toggle is not undone
+    async fn toggleTodo(context: &Context, id: ID) -> Result<Todo, GqlError> {
+        let claims = context.claims_with(WRITE_SCOPE)?;
+        let id = required_todo_id(&id)?;
+        let scope = claims.scope();
+        let todo = db_get_todo(id, &scope, &context.pool).await?;
+        let patch = UpdateTodo { text: None, completed: Some(!todo.completed), version: Some(todo.version) };
+        let todo = db_patch_todo(id, patch, &claims.sub, &scope, &context.pool).await?;
+        context.loader.prime(id, Some(todo.clone())).await;
+        Ok(todo)
+    }
+
+    // Moves the todo to the trash, REST can restore it
+    async fn deleteTodo(context: &Context, id: ID, version: Option<i32>) -> Result<Todo, GqlError> {
+        let claims = context.claims_with(WRITE_SCOPE)?;
+        let id = required_todo_id(&id)?;
+        let todo = db_delete_todo(id, version, &claims.sub, &claims.scope(), &context.pool).await?;
+        context.loader.prime(id, None).await;
+        Ok(todo)
+    }
+
+    // Returns the todos moved to the trash
+    async fn clearCompleted(context: &Context) -> Result<Vec<Todo>, GqlError> {
+        let claims = context.claims_with(WRITE_SCOPE)?;
+        let todos = db_clear_completed(&claims.sub, &claims.scope(), &context.pool).await?;
+        for todo in &todos {
+            context.loader.prime(todo.id, None).await;
+        }
+        Ok(todos)
+    }
+}
+
+type TodoStream = Pin<Box<dyn Stream<Item = Result<Todo, FieldError>> + Send>>;
+
+/// The todos of the caller touched by `action`, a subscriber lagging
+/// too far behind the bus skips the events it missed.
+fn todo_stream(context: &Context, action: Action) -> TodoStream {
+    let scope = match context.claims_with(READ_SCOPE) {
+        Ok(claims) => claims.scope(),
+        Err(e) => return Box::pin(stream::once(future::ready(Err(GqlError::from(e).into_field_error())))),
+    };
+    let todos = context.events.subscribe().filter_map(move |event| {
+        let todo = match event {
+            Ok(event) if event.action == action && scope.allows(&event.todo) => Some(Ok(event.todo)),
+            _ => None,
+        };
+        future::ready(todo)
+    });
+    Box::pin(todos)
+}
+
+pub struct Subscription;
+
+#[juniper::graphql_subscription(Context = Context,)]
+impl Subscription {
+    async fn todoCreated(context: &Context) -> TodoStream {
+        todo_stream(context, Action::Created)
+    }
+
+    async fn todoUpdated(context: &Context) -> TodoStream {
+        todo_stream(context, Action::Updated)
+    }
+
+    // The todos as they were before their deletion
+    async fn todoDeleted(context: &Context) -> TodoStream {
+        todo_stream(context, Action::Deleted)
+    }
+}
+
+pub type Schema = juniper::RootNode<'static, Query, Mutation, Subscription>;
+
+pub fn schema() -> Schema {
+    Schema::new(Query, Mutation, Subscription)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::events::EventBus;
+    use super::super::models::{ApiKey, AuditOptions, BulkOperation, Cursor, MemoryTodoRepository, Page, TodoFilter, TodoRepository, TodoRows};
+    use async_trait::async_trait;
+    use juniper::http::GraphQLRequest;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Counts the reads of todos by id.
+    struct CountingRepository {
+        inner: MemoryTodoRepository,
+        reads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl TodoRepository for CountingRepository {
+        async fn get_todo(&self, id: i32, scope: &Scope) -> Result<Todo, TodoError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.get_todo(id, scope).await
+        }
+
+        async fn get_todos(&self, ids: &[i32], scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.get_todos(ids, scope).await
+        }
+
+        async fn list_todos(&self, filter: &TodoFilter, offset: i64, limit: i64) -> Result<Page<Todo>, TodoError> {
+            self.inner.list_todos(filter, offset, limit).await
+        }
+
+        async fn list_todos_after(&self, filter: &TodoFilter, after: Option<&Cursor>, limit: i64) -> Result<Vec<Todo>, TodoError> {
+            self.inner.list_todos_after(filter, after, limit).await
+        }
+
+        async fn list_todos_before(&self, filter: &TodoFilter, before: Option<&Cursor>, limit: i64) -> Result<Vec<Todo>, TodoError> {
+            self.inner.list_todos_before(filter, before, limit).await
+        }
+
+        async fn count_todos(&self, filter: &TodoFilter) -> Result<i64, TodoError> {
+            self.inner.count_todos(filter).await
+        }
+
+        async fn create_todo(&self, text: String, completed: bool, actor: &str) -> Result<Todo, TodoError> {
+            self.inner.create_todo(text, completed, actor).await
+        }
+
+        async fn update_todo(&self, id: i32, text: String, completed: bool, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+            self.inner.update_todo(id, text, completed, version, actor, scope).await
+        }
+
+        async fn patch_todo(&self, id: i32, text: Option<String>, completed: Option<bool>, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+            self.inner.patch_todo(id, text, completed, version, actor, scope).await
+        }
+
+        async fn delete_todo(&self, id: i32, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+            self.inner.delete_todo(id, version, actor, scope).await
+        }
+
+        async fn clear_completed(&self, actor: &str, scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+            self.inner.clear_completed(actor, scope).await
+        }
+
+        async fn list_trash(&self, scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+            self.inner.list_trash(scope).await
+        }
+
+        async fn restore_todo(&self, id: i32, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+            self.inner.restore_todo(id, actor, scope).await
+        }
+
+        async fn purge_trash(&self, deleted_before: DateTime<Utc>) -> Result<u64, TodoError> {
+            self.inner.purge_trash(deleted_before).await
+        }
+
+        async fn bulk(&self, operations: Vec<BulkOperation>, atomic: bool, actor: &str, scope: &Scope) -> Result<Vec<Result<Todo, TodoError>>, TodoError> {
+            self.inner.bulk(operations, atomic, actor, scope).await
+        }
+
+        async fn todo_history(&self, id: i32, scope: &Scope) -> Result<Vec<AuditEntry>, TodoError> {
+            self.inner.todo_history(id, scope).await
+        }
+
+        async fn list_audit(&self, opts: &AuditOptions, limit: i64) -> Result<Vec<AuditEntry>, TodoError> {
+            self.inner.list_audit(opts, limit).await
+        }
+
+        async fn export_todos(&self, scope: &Scope) -> Result<TodoRows, TodoError> {
+            self.inner.export_todos(scope).await
+        }
+
+        async fn create_api_key(&self, prefix: String, salt: String, hash: String, name: String, owner_id: String, scopes: String) -> Result<ApiKey, TodoError> {
+            self.inner.create_api_key(prefix, salt, hash, name, owner_id, scopes).await
+        }
+
+        async fn list_api_keys(&self, owner_id: &str) -> Result<Vec<ApiKey>, TodoError> {
+            self.inner.list_api_keys(owner_id).await
+        }
+
+        async fn revoke_api_key(&self, id: i32, owner_id: &str) -> Result<(), TodoError> {
+            self.inner.revoke_api_key(id, owner_id).await
+        }
+
+        async fn find_api_key(&self, prefix: &str) -> Result<Option<ApiKey>, TodoError> {
+            self.inner.find_api_key(prefix).await
+        }
+    }
+
+    async fn setup() -> (Arc<CountingRepository>, Context) {
+        let repository = Arc::new(CountingRepository { inner: MemoryTodoRepository::new(), reads: AtomicUsize::new(0) });
+        for text in &["milk", "eggs", "bread"] {
+            repository.create_todo(text.to_string(), false, "alice").await.unwrap();
+        }
+        let claims = Claims { sub: "alice".to_string(), roles: Vec::new(), exp: 0, scopes: None };
+        let context = Context::new(repository.clone(), Some(claims), EventBus::new());
+        (repository, context)
+    }
+
+    async fn execute(query: &str, variables: Option<InputValue>, context: &Context) -> serde_json::Value {
+        let schema = schema();
+        let todos = requested_todos(&schema, query, None, variables.as_ref());
+        context.prefetch(&todos).await;
+        let response = GraphQLRequest::new(query.to_string(), None, variables).execute(&schema, context).await;
+        assert!(response.is_ok(), "{:?}", serde_json::to_string(&response));
+        serde_json::to_value(&response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn todos_of_a_query_are_read_once() {
+        let (repository, context) = setup().await;
+        let query = r#"query Todos($id: ID!) {
+            a: todo(id: 1) { text }
+            b: todo(id: $id) { text }
+            ...more
+        }
+        fragment more on Query {
+            c: node(id: "VG9kbzoz") { ... on Todo { text } }
+            d: todo(id: 4) { text }
+        }"#;
+        let variables = serde_json::from_str(r#"{"id": "2"}"#).unwrap();
+        let response = execute(query, Some(variables), &context).await;
+        assert_eq!(response["data"]["a"]["text"], "milk");
+        assert_eq!(response["data"]["b"]["text"], "eggs");
+        assert_eq!(response["data"]["c"]["text"], "bread");
+        assert!(response["data"]["d"].is_null());
+        assert_eq!(repository.reads.load(Ordering::SeqCst), 1);
+
+        // Already loaded
+        execute("{ todo(id: 3) { text } }", None, &context).await;
+        assert_eq!(repository.reads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn global_and_database_ids() {
+        let (_, context) = setup().await;
+        let response = execute(r#"{ a: todo(id: "VG9kbzox") { id databaseId } b: todo(id: 2) { id } c: todo(id: "2") { id } d: todo(id: "bm9wZTox") { id } }"#, None, &context).await;
+        assert_eq!(response["data"]["a"], serde_json::json!({ "id": "VG9kbzox", "databaseId": 1 }));
+        assert_eq!(response["data"]["b"]["id"], "VG9kbzoy");
+        assert_eq!(response["data"]["c"]["id"], "VG9kbzoy");
+        assert!(response["data"]["d"].is_null());
+
+        // What a query returns is what the mutations take
+        let response = execute(r#"mutation { toggleTodo(id: "VG9kbzoz") { databaseId completed } }"#, None, &context).await;
+        assert_eq!(response["data"]["toggleTodo"], serde_json::json!({ "databaseId": 3, "completed": true }));
+        let response = execute("mutation { deleteTodo(id: 3) { databaseId } }", None, &context).await;
+        assert_eq!(response["data"]["deleteTodo"]["databaseId"], 3);
+    }
+
+    #[tokio::test]
+    async fn trashed_todos_are_hidden() {
+        let (repository, context) = setup().await;
+        // Behind the back of the loader, which would answer from what deleteTodo left
+        repository.delete_todo(1, None, "alice", &Scope::Everything).await.unwrap();
+        let response = execute(r#"{
+            all: todosList(opt: {}) { databaseId }
+            keyset: todosList(opt: { after: "" }) { databaseId }
+            search: todosList(opt: { q: "milk" }) { databaseId }
+            todo(id: 1) { text }
+        }"#, None, &context).await;
+        assert_eq!(response["data"]["all"], serde_json::json!([{ "databaseId": 2 }, { "databaseId": 3 }]));
+        assert_eq!(response["data"]["keyset"], response["data"]["all"]);
+        assert_eq!(response["data"]["search"], serde_json::json!([]));
+        assert!(response["data"]["todo"].is_null());
+    }
+
+    #[tokio::test]
+    async fn without_prefetch_each_todo_is_read_once() {
+        let (repository, context) = setup().await;
+        let query = "{ a: todo(id: 2) { text } b: todo(id: 2) { text } c: todo(id: 3) { text } }";
+        let response = GraphQLRequest::new(query.to_string(), None, None).execute(&schema(), &context).await;
+        assert!(response.is_ok());
+        assert_eq!(repository.reads.load(Ordering::SeqCst), 2);
+    }
+}
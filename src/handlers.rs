@@ -0,0 +1,402 @@
+//! These are ou API handlers, the ends of each filter chain.
+//! Notice how thanks to using `Filter::and`, we can define a function
+//! with the exact arguments we'd expect from each filter in the chain.
+//! No tuples are needed, it's auto flattened for the functions.
+
+use super::auth::{self, AuthError, Claims};
+use super::events::Events;
+use super::models::{ApiKeyCreated, AuditOptions, BulkMode, BulkRequest, ClearOptions, CreateApiKey, CreateTodo, CursorPage, Db, FormatOptions, ListOptions, Page, ReplaceTodo, Scope, Todo, TodoError, TodoFormat, UpdateTodo};
+use super::models::{db_get_todo, db_list_todos, db_list_todos_after, db_create_todos, db_update_todo, db_patch_todo, db_delete_todo, db_clear_completed, db_bulk, db_export_todos, db_import_todos};
+use super::models::{db_list_trash, db_restore_todo, db_todo_history, db_list_audit};
+use super::models::{db_create_api_key, db_list_api_keys, db_revoke_api_key};
+use bytes::Bytes;
+use futures::{future, stream, StreamExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use warp::http::header::{HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, LINK, WWW_AUTHENTICATE};
+use warp::http::StatusCode;
+use warp::{reject, Rejection, Reply};
+
+pub async fn list_todos(claims: Claims, opts: ListOptions, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let scope = claims.scope();
+    let (mut response, links) = if opts.after.is_some() {
+        let page = db_list_todos_after(&opts, &scope, &db).await
+            .map_err(reject::custom)?;
+        (warp::reply::json(&page).into_response(), cursor_page_links(&opts, &page))
+    } else {
+        let page = db_list_todos(&opts, &scope, &db).await
+            .map_err(reject::custom)?;
+        (warp::reply::json(&page).into_response(), page_links(&opts, &page))
+    };
+    if !links.is_empty() {
+        // The query strings are url encoded, the conversion can't fail
+        if let Ok(value) = HeaderValue::from_str(&links.join(", ")) {
+            response.headers_mut().insert(LINK, value);
+        }
+    }
+    Ok(response)
+}
+
+/// A RFC 8288 link to the list with `opts`, the filters of the request are kept.
+fn link(opts: &ListOptions, rel: &str) -> String {
+    let query = serde_urlencoded::to_string(opts).unwrap_or_default();
+    format!("</todos?{}>; rel=\"{}\"", query, rel)
+}
+
+/// The links to the pages around `page`.
+fn page_links(opts: &ListOptions, page: &Page<Todo>) -> Vec<String> {
+    let mut links = Vec::new();
+    let mut opts = opts.clone();
+    opts.limit = Some(page.limit as i32);
+    if page.offset + page.limit < page.total {
+        opts.offset = Some((page.offset + page.limit) as i32);
+        links.push(link(&opts, "next"));
+    }
+    if page.offset > 0 {
+        opts.offset = Some((page.offset - page.limit).max(0) as i32);
+        links.push(link(&opts, "prev"));
+    }
+    links
+}
+
+/// A keyset page only knows the way forward.
+fn cursor_page_links(opts: &ListOptions, page: &CursorPage<Todo>) -> Vec<String> {
+    match &page.next {
+        Some(next) => {
+            let mut opts = opts.clone();
+            opts.after = Some(next.clone());
+            opts.limit = Some(page.limit as i32);
+            vec![link(&opts, "next")]
+        }
+        None => Vec::new(),
+    }
+}
+
+pub async fn get_todo(id: i32, claims: Claims, if_none_match: Option<String>, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let todo = db_get_todo(id, &claims.scope(), &db).await
+        .map_err(reject::custom)?;
+    let etag = etag(&todo);
+    // If-None-Match uses the weak comparison, a W/ prefix doesn't matter
+    let not_modified = if_none_match.map_or(false, |tags| {
+        tags.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        })
+    });
+    let response = if not_modified {
+        warp::reply::with_status(warp::reply(), StatusCode::NOT_MODIFIED).into_response()
+    } else {
+        warp::reply::json(&todo).into_response()
+    };
+    Ok(with_etag(response, &etag))
+}
+
+pub async fn create_todos(claims: Claims, create: CreateTodo, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let todo = db_create_todos(create.text, create.completed, &claims.sub, &db).await
+        .map_err(reject::custom)?;
+    let location = format!("/todos/{}", todo.id);
+    let reply = warp::reply::with_status(warp::reply::json(&todo), StatusCode::CREATED);
+    Ok(warp::reply::with_header(reply, "location", location))
+}
+
+pub async fn update_todo(id: i32, claims: Claims, if_match: Option<String>, update: ReplaceTodo, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let scope = claims.scope();
+    // The version matched by If-Match makes sure nobody wrote in between
+    let version = update.version.or(check_if_match(id, &scope, if_match, &db).await?);
+    let todo = db_update_todo(id, update.text, update.completed, version, &claims.sub, &scope, &db).await
+        .map_err(reject::custom)?;
+    let etag = etag(&todo);
+    Ok(with_etag(warp::reply::json(&todo).into_response(), &etag))
+}
+
+pub async fn patch_todo(id: i32, claims: Claims, if_match: Option<String>, mut patch: UpdateTodo, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let scope = claims.scope();
+    // As for PUT, a stale If-Match fails even when the body has a version
+    let matched = check_if_match(id, &scope, if_match, &db).await?;
+    patch.version = patch.version.or(matched);
+    let todo = db_patch_todo(id, patch, &claims.sub, &scope, &db).await
+        .map_err(reject::custom)?;
+    let etag = etag(&todo);
+    Ok(with_etag(warp::reply::json(&todo).into_response(), &etag))
+}
+
+// The events of the todos visible to the caller, with a keep-alive comment when idle
+pub async fn todo_events(claims: Claims, last_event_id: Option<u64>, events: Events) -> Result<impl warp::Reply, Rejection> {
+    let scope = claims.scope();
+    let stream = events.subscribe_since(last_event_id).await
+        .filter(move |event| future::ready(scope.allows(&event.todo)))
+        .map(|event| Ok::<_, Infallible>((
+            warp::sse::id(event.id),
+            warp::sse::event(event.action.as_str()),
+            warp::sse::json(event.todo),
+        )));
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
+
+pub async fn list_api_keys(claims: Claims, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let api_keys = db_list_api_keys(&claims.sub, &db).await
+        .map_err(reject::custom)?;
+    Ok(warp::reply::json(&api_keys))
+}
+
+pub async fn create_api_key(claims: Claims, create: CreateApiKey, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let generated = auth::generate_api_key();
+    let api_key = db_create_api_key(create, generated.prefix, generated.salt, generated.hash, claims.sub, &db).await
+        .map_err(reject::custom)?;
+    let location = format!("/api-keys/{}", api_key.id);
+    let created = ApiKeyCreated { api_key, key: generated.key };
+    let reply = warp::reply::with_status(warp::reply::json(&created), StatusCode::CREATED);
+    Ok(warp::reply::with_header(reply, "location", location))
+}
+
+pub async fn revoke_api_key(id: i32, claims: Claims, db: Db) -> Result<impl warp::Reply, Rejection> {
+    db_revoke_api_key(id, &claims.sub, &db).await
+        .map_err(reject::custom)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The strong ETag of a todo, a hash of its JSON representation.
+pub fn etag(todo: &Todo) -> String {
+    // Serializing a todo can't fail
+    let json = serde_json::to_vec(todo).unwrap_or_default();
+    format!("\"{:x}\"", Sha256::digest(&json))
+}
+
+fn with_etag(mut response: warp::reply::Response, etag: &str) -> warp::reply::Response {
+    // The ETag is an hexadecimal hash between quotes, the conversion can't fail
+    if let Ok(value) = HeaderValue::from_str(etag) {
+        response.headers_mut().insert(ETAG, value);
+    }
+    response
+}
+
+/// Refuse the write when the `If-Match` header doesn't match the current todo.
+/// If-Match uses the strong comparison, weak tags never match.
+/// Returns the version of the matched todo, the write must then happen on this version.
+async fn check_if_match(id: i32, scope: &Scope, if_match: Option<String>, db: &Db) -> Result<Option<i32>, Rejection> {
+    let if_match = match if_match {
+        Some(if_match) => if_match,
+        None => return Ok(None),
+    };
+    let todo = db_get_todo(id, scope, db).await
+        .map_err(reject::custom)?;
+    let etag = etag(&todo);
+    if if_match.split(',').any(|tag| tag.trim() == "*" || tag.trim() == etag) {
+        Ok(Some(todo.version))
+    } else {
+        Err(reject::custom(PreconditionFailed))
+    }
+}
+
+/// Parse the body of a PATCH, either plain JSON or JSON Merge Patch (RFC 7396).
+/// With a merge patch a `null` member removes the field, which no field of a todo allows.
+pub async fn parse_patch(content_type: Option<String>, body: Bytes) -> Result<UpdateTodo, Rejection> {
+    let media_type = content_type.as_deref()
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|media_type| media_type.trim().to_lowercase());
+    let merge_patch = match media_type.as_deref() {
+        // like `warp::body::json()`, a missing content-type is taken as JSON
+        None | Some("application/json") => false,
+        Some("application/merge-patch+json") => true,
+        _ => return Err(reject::custom(UnsupportedMediaType)),
+    };
+    let value: serde_json::Value = serde_json::from_slice(&body)
+        .map_err(|e| reject::custom(InvalidBody(e.to_string())))?;
+    if merge_patch {
+        if let Some(fields) = value.as_object() {
+            if let Some((field, _)) = fields.iter().find(|(_, value)| value.is_null()) {
+                let error = TodoError::Validation(format!("{} can't be removed", field));
+                return Err(reject::custom(error));
+            }
+        }
+    }
+    serde_json::from_value(value)
+        .map_err(|e| reject::custom(InvalidBody(e.to_string())))
+}
+
+pub async fn delete_todo(id: i32, claims: Claims, if_match: Option<String>, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let scope = claims.scope();
+    let version = check_if_match(id, &scope, if_match, &db).await?;
+    db_delete_todo(id, version, &claims.sub, &scope, &db).await
+        .map_err(reject::custom)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// 200 when every operation succeeded. Otherwise 207 when some were written,
+// or the status of the failed operation when all or nothing was rolled back.
+pub async fn bulk_todos(claims: Claims, request: BulkRequest, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let response = db_bulk(request, &claims.sub, &claims.scope(), &db).await
+        .map_err(reject::custom)?;
+    let failed = response.results.iter().find(|result| result.error.is_some() && result.status != StatusCode::FAILED_DEPENDENCY.as_u16());
+    let status = match (failed, response.mode) {
+        (None, _) => StatusCode::OK,
+        (Some(_), BulkMode::BestEffort) => StatusCode::MULTI_STATUS,
+        (Some(failed), BulkMode::AllOrNothing) => StatusCode::from_u16(failed.status).unwrap_or(StatusCode::BAD_REQUEST),
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&response), status))
+}
+
+// Returns the deleted todos
+pub async fn clear_todos(claims: Claims, opts: ClearOptions, db: Db) -> Result<impl warp::Reply, Rejection> {
+    if opts.completed != Some(true) {
+        let error = TodoError::Validation("DELETE /todos only clears the completed todos, add completed=true".to_string());
+        return Err(reject::custom(error));
+    }
+    let todos = db_clear_completed(&claims.sub, &claims.scope(), &db).await
+        .map_err(reject::custom)?;
+    Ok(warp::reply::json(&todos))
+}
+
+pub async fn list_trash(claims: Claims, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let todos = db_list_trash(&claims.scope(), &db).await
+        .map_err(reject::custom)?;
+    Ok(warp::reply::json(&todos))
+}
+
+pub async fn restore_todo(id: i32, claims: Claims, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let todo = db_restore_todo(id, &claims.sub, &claims.scope(), &db).await
+        .map_err(reject::custom)?;
+    let etag = etag(&todo);
+    Ok(with_etag(warp::reply::json(&todo).into_response(), &etag))
+}
+
+pub async fn todo_history(id: i32, claims: Claims, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let history = db_todo_history(id, &claims.scope(), &db).await
+        .map_err(reject::custom)?;
+    Ok(warp::reply::json(&history))
+}
+
+pub async fn list_audit(_claims: Claims, opts: AuditOptions, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let entries = db_list_audit(&opts, &db).await
+        .map_err(reject::custom)?;
+    Ok(warp::reply::json(&entries))
+}
+
+// The todos are written as they are read, a storage failure cuts the body short
+pub async fn export_todos(claims: Claims, opts: FormatOptions, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let format = TodoFormat::parse(opts.format.as_deref())
+        .map_err(reject::custom)?;
+    let todos = db_export_todos(&claims.scope(), &db).await
+        .map_err(reject::custom)?;
+    let header = stream::iter(format.header().map(|header| Ok::<_, TodoError>(header.to_string())));
+    let lines = todos.map(move |todo| todo.map(|todo| format.line(&todo)));
+    let mut response = warp::reply::Response::new(warp::hyper::Body::wrap_stream(header.chain(lines)));
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+    let disposition = format!("attachment; filename=\"todos.{}\"", format.extension());
+    // The file name only holds ascii letters, the conversion can't fail
+    if let Ok(value) = HeaderValue::from_str(&disposition) {
+        response.headers_mut().insert(CONTENT_DISPOSITION, value);
+    }
+    Ok(response)
+}
+
+#[derive(Serialize)]
+struct ImportSummary {
+    imported: usize,
+}
+
+// Nothing is imported when a line is invalid, the errors tell which ones
+pub async fn import_todos(claims: Claims, opts: FormatOptions, body: Bytes, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let format = TodoFormat::parse(opts.format.as_deref())
+        .map_err(reject::custom)?;
+    let body = std::str::from_utf8(&body)
+        .map_err(|e| reject::custom(InvalidBody(e.to_string())))?;
+    let todos = db_import_todos(format, body, &claims.sub, &claims.scope(), &db).await
+        .map_err(reject::custom)?;
+    let summary = ImportSummary { imported: todos.len() };
+    Ok(warp::reply::with_status(warp::reply::json(&summary), StatusCode::CREATED))
+}
+
+/// The body of the request could not be parsed.
+#[derive(Debug)]
+pub struct InvalidBody(String);
+
+impl reject::Reject for InvalidBody {}
+
+/// The todo was changed since the client fetched it.
+#[derive(Debug)]
+pub struct PreconditionFailed;
+
+impl reject::Reject for PreconditionFailed {}
+
+/// The `content-type` of the request is not supported by the route.
+#[derive(Debug)]
+pub struct UnsupportedMediaType;
+
+impl reject::Reject for UnsupportedMediaType {}
+
+#[derive(Serialize)]
+struct ErrorMessage {
+    code: u16,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>
+}
+
+// This function receives a `Rejection` and tries to return a JSON error
+// with the matching status code, otherwise simply passes the rejection along.
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let code;
+    let message;
+    let mut details = None;
+
+    if err.is_not_found() {
+        code = StatusCode::NOT_FOUND;
+        message = "NOT_FOUND".to_string();
+    } else if let Some(e) = err.find::<AuthError>() {
+        code = e.status();
+        message = e.to_string();
+    } else if let Some(e) = err.find::<TodoError>() {
+        code = e.status();
+        message = e.to_string();
+        details = e.details();
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        code = StatusCode::BAD_REQUEST;
+        message = format!("INVALID_BODY: {}", e);
+    } else if let Some(InvalidBody(e)) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = format!("INVALID_BODY: {}", e);
+    } else if let Some(PreconditionFailed) = err.find() {
+        code = StatusCode::PRECONDITION_FAILED;
+        message = "PRECONDITION_FAILED".to_string();
+    } else if let Some(UnsupportedMediaType) = err.find() {
+        code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        message = "UNSUPPORTED_MEDIA_TYPE".to_string();
+    } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        message = "UNSUPPORTED_MEDIA_TYPE".to_string();
+    } else if err.find::<warp::reject::LengthRequired>().is_some() {
+        code = StatusCode::LENGTH_REQUIRED;
+        message = "LENGTH_REQUIRED".to_string();
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        code = StatusCode::BAD_REQUEST;
+        message = format!("INVALID_HEADER: {}", e.name());
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        code = StatusCode::BAD_REQUEST;
+        message = format!("MISSING_HEADER: {}", e.name());
+    } else if err.find::<warp::reject::InvalidQuery>().is_some() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_QUERY".to_string();
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        code = StatusCode::PAYLOAD_TOO_LARGE;
+        message = "PAYLOAD_TOO_LARGE".to_string();
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        code = StatusCode::METHOD_NOT_ALLOWED;
+        message = "METHOD_NOT_ALLOWED".to_string();
+    } else {
+        code = StatusCode::INTERNAL_SERVER_ERROR;
+        message = "UNHANDLED_REJECTION".to_string();
+    }
+
+    let json = warp::reply::json(&ErrorMessage{
+        code: code.as_u16(),
+        message,
+        details,
+    });
+    let mut response = warp::reply::with_status(json, code).into_response();
+    if code == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    }
+    Ok(response)
+}
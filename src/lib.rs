@@ -0,0 +1,9 @@
+//! The todos shared by the `todo` REST server and the `todo_graph` GraphQL server:
+//! their storage, authentication, change events, REST routes and GraphQL schema.
+
+pub mod auth;
+pub mod events;
+pub mod filters;
+pub mod gql;
+pub mod handlers;
+pub mod models;
@@ -0,0 +1,1915 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "postgres")]
+use sqlx::pool::PoolConnection;
+#[cfg(feature = "postgres")]
+use sqlx::{PgConnection, PgPool, Transaction};
+use futures::{stream, Stream};
+#[cfg(feature = "postgres")]
+use futures::StreamExt;
+use std::cmp::Ordering;
+use std::env;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::http::StatusCode;
+
+// The storage is hidden behind `TodoRepository`, so the server can run either
+// against Postgres or against a simple in-memory DB, a vector synchronized by Mutex.
+// Postgres comes with the `postgres` feature, on by default: its queries are checked
+// against `DATABASE_URL` at compile time, `--no-default-features` builds without a database.
+pub type Db = Arc<dyn TodoRepository>;
+
+/// Todos read one at a time, so a whole table never sits in memory.
+pub type TodoRows = Pin<Box<dyn Stream<Item = Result<Todo, TodoError>> + Send>>;
+
+#[derive(Debug, Deserialize, Serialize, Clone)] //, sqlx::FromRow
+pub struct Todo {
+    pub id: i32,
+    pub text: String,
+    pub completed: bool,
+    /// Incremented by every update, used to detect concurrent writes
+    pub version: i32,
+    /// The subject of the token which created the todo
+    pub owner_id: String,
+    /// When the todo was moved to the trash, it is purged after the retention
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// The todos a caller is allowed to see and change.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scope {
+    Everything,
+    Owner(String),
+}
+
+impl Scope {
+    /// The owner to filter on, `None` when every todo is visible.
+    pub fn owner(&self) -> Option<&str> {
+        match self {
+            Scope::Everything => None,
+            Scope::Owner(owner) => Some(owner),
+        }
+    }
+
+    pub fn allows(&self, todo: &Todo) -> bool {
+        self.owner().map_or(true, |owner| todo.owner_id == owner)
+    }
+}
+
+// The JSON body of POST /todos, the id comes from the SERIAL column.
+// Also a line of a JSON Lines import, where the other fields of an export are ignored.
+#[derive(Debug, Deserialize)]
+pub struct CreateTodo {
+    pub text: String,
+    #[serde(default)]
+    pub completed: bool
+}
+
+// The input of the GraphQL createTodo, same as CreateTodo.
+#[derive(juniper::GraphQLInputObject)]
+#[graphql(description="A new todo, not completed unless told otherwise")]
+pub struct NewTodo {
+    pub text: String,
+    pub completed: Option<bool>
+}
+
+// The JSON body of PUT /todos/:id, an `id` in the body is ignored for the one of the path.
+// When `version` is given the update only happens on that version of the todo.
+#[derive(Debug, Deserialize)]
+pub struct ReplaceTodo {
+    pub text: String,
+    pub completed: bool,
+    pub version: Option<i32>
+}
+
+// The JSON body of PATCH /todos/:id, only the present fields are updated.
+// Also the `patch` argument of the GraphQL updateTodo.
+#[derive(Debug, Deserialize)]
+#[derive(juniper::GraphQLInputObject)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateTodo {
+    pub text: Option<String>,
+    pub completed: Option<bool>,
+    pub version: Option<i32>
+}
+
+// The query parameters for list_todos, also the `opt` argument of the GraphQL todosList.
+// `after` switches to keyset paging, an empty `after` starts from the first todo.
+// `completed` and `q` filter the todos, `sort` is one of id, -id, text or completed.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[derive(juniper::GraphQLInputObject)]
+pub struct ListOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<String>,
+}
+
+// The query parameters of DELETE /todos, which only clears the completed todos for now.
+#[derive(Debug, Deserialize)]
+pub struct ClearOptions {
+    pub completed: Option<bool>,
+}
+
+/// One operation of POST /todos/bulk, `update` only changes the fields which are present.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+pub enum BulkOperation {
+    Create {
+        text: String,
+        #[serde(default)]
+        completed: bool,
+    },
+    Update {
+        id: i32,
+        text: Option<String>,
+        completed: Option<bool>,
+        version: Option<i32>,
+    },
+    Delete {
+        id: i32,
+        version: Option<i32>,
+    },
+}
+
+/// `all_or_nothing` writes every operation or none, `best_effort` keeps the ones which succeeded.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkMode {
+    AllOrNothing,
+    BestEffort,
+}
+
+impl Default for BulkMode {
+    fn default() -> Self {
+        BulkMode::AllOrNothing
+    }
+}
+
+// The JSON body of POST /todos/bulk.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BulkRequest {
+    #[serde(default)]
+    pub mode: BulkMode,
+    pub operations: Vec<BulkOperation>,
+}
+
+/// What happened to one operation of a bulk request, `index` is its position in the request.
+#[derive(Debug, Serialize)]
+pub struct BulkResult {
+    pub index: usize,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub todo: Option<Todo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The answer to POST /todos/bulk, `committed` tells if anything was written.
+#[derive(Debug, Serialize)]
+pub struct BulkResponse {
+    pub mode: BulkMode,
+    pub committed: bool,
+    pub results: Vec<BulkResult>,
+}
+
+/// Most operations in a bulk request.
+pub const MAX_BULK_OPERATIONS: usize = 1000;
+
+/// What a write did to a todo, as recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    Created,
+    Updated,
+    /// Moved to the trash
+    Deleted,
+    /// Taken out of the trash
+    Restored,
+    /// Removed from the trash for good
+    Purged,
+}
+
+impl AuditAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::Created => "created",
+            AuditAction::Updated => "updated",
+            AuditAction::Deleted => "deleted",
+            AuditAction::Restored => "restored",
+            AuditAction::Purged => "purged",
+        }
+    }
+}
+
+/// The actor of the writes made by the server itself, like the purge of the trash.
+pub const SYSTEM_ACTOR: &str = "system";
+
+/// A write of a todo, as kept in the `todo_events` table. The snapshots are the todo as JSON,
+/// `before` is missing for a creation and `after` for a purge.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub todo_id: i32,
+    /// The subject of the token or of the API key which made the write
+    pub actor: String,
+    pub action: String,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AuditEntry {
+    pub fn new(id: i32, actor: &str, action: AuditAction, before: Option<&Todo>, after: Option<&Todo>) -> Self {
+        // Serializing a todo can't fail
+        let snapshot = |todo: Option<&Todo>| todo.and_then(|todo| serde_json::to_value(todo).ok());
+        AuditEntry {
+            id,
+            todo_id: after.or(before).map_or(0, |todo| todo.id),
+            actor: actor.to_string(),
+            action: action.as_str().to_string(),
+            before: snapshot(before),
+            after: snapshot(after),
+            created_at: Utc::now(),
+        }
+    }
+}
+
+// The query parameters of GET /audit, the entries are listed newest first.
+// `before` is the id of the last entry seen, to fetch the next page.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditOptions {
+    pub actor: Option<String>,
+    pub todo_id: Option<i32>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub before: Option<i32>,
+    pub limit: Option<i32>,
+}
+
+impl AuditOptions {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.actor.as_ref().map_or(true, |actor| &entry.actor == actor)
+            && self.todo_id.map_or(true, |todo_id| entry.todo_id == todo_id)
+            && self.since.map_or(true, |since| entry.created_at >= since)
+            && self.until.map_or(true, |until| entry.created_at < until)
+            && self.before.map_or(true, |before| entry.id < before)
+    }
+}
+
+/// Days a todo stays in the trash when `TRASH_RETENTION_DAYS` is not set.
+pub const DEFAULT_RETENTION_DAYS: i64 = 30;
+/// How often the trash is purged.
+pub const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+// The query parameters of GET /todos/export and POST /todos/import.
+#[derive(Debug, Deserialize)]
+pub struct FormatOptions {
+    pub format: Option<String>,
+}
+
+/// The formats of the exports and imports, one todo per line or per record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TodoFormat {
+    /// A JSON todo per line
+    JsonLines,
+    /// `id,text,completed,version,owner_id` with a header line
+    Csv,
+    /// A `- [x] text` task list, the line breaks of the texts become spaces
+    Markdown,
+}
+
+impl TodoFormat {
+    /// JSON Lines unless told otherwise.
+    pub fn parse(format: Option<&str>) -> Result<Self, TodoError> {
+        match format.unwrap_or("jsonl") {
+            "jsonl" => Ok(TodoFormat::JsonLines),
+            "csv" => Ok(TodoFormat::Csv),
+            "md" => Ok(TodoFormat::Markdown),
+            format => Err(TodoError::Validation(format!("unknown format {}, expected jsonl, csv or md", format))),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TodoFormat::JsonLines => "jsonl",
+            TodoFormat::Csv => "csv",
+            TodoFormat::Markdown => "md",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TodoFormat::JsonLines => "application/x-ndjson",
+            TodoFormat::Csv => "text/csv; charset=utf-8",
+            TodoFormat::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+
+    /// What comes before the todos.
+    pub fn header(&self) -> Option<&'static str> {
+        match self {
+            TodoFormat::Csv => Some("id,text,completed,version,owner_id\n"),
+            _ => None,
+        }
+    }
+
+    /// One todo, with its line break.
+    pub fn line(&self, todo: &Todo) -> String {
+        match self {
+            // Serializing a todo can't fail
+            TodoFormat::JsonLines => format!("{}\n", serde_json::to_string(todo).unwrap_or_default()),
+            TodoFormat::Csv => format!("{},{},{},{},{}\n", todo.id, csv_field(&todo.text), todo.completed, todo.version, csv_field(&todo.owner_id)),
+            TodoFormat::Markdown => {
+                let check = if todo.completed { 'x' } else { ' ' };
+                format!("- [{}] {}\n", check, todo.text.split_whitespace().collect::<Vec<_>>().join(" "))
+            }
+        }
+    }
+
+    /// The todos of an import, or the errors of every invalid line.
+    pub fn parse_todos(&self, body: &str) -> Result<Vec<CreateTodo>, TodoError> {
+        let rows = match self {
+            TodoFormat::JsonLines => body.lines().enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(index, line)| (index + 1, serde_json::from_str::<CreateTodo>(line).map_err(|e| e.to_string())))
+                .collect(),
+            TodoFormat::Csv => csv_todos(body)?,
+            TodoFormat::Markdown => body.lines().enumerate()
+                .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+                .map(|(index, line)| (index + 1, markdown_todo(line)))
+                .collect(),
+        };
+        let mut todos = Vec::new();
+        let mut errors = Vec::new();
+        for (line, row) in rows {
+            match row.and_then(|todo| validate_text(&todo.text).map(|_| todo).map_err(|e| e.to_string())) {
+                Ok(todo) => todos.push(todo),
+                Err(message) => errors.push(ImportError { line, message }),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(TodoError::InvalidImport(errors));
+        }
+        Ok(todos)
+    }
+}
+
+/// A CSV field, quoted when it holds a comma, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// The records of a CSV document with the line they start on,
+/// a quoted field can hold commas, doubled quotes and line breaks.
+fn csv_records(body: &str) -> Result<Vec<(usize, Vec<String>)>, TodoError> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let (mut line, mut start) = (1, 1);
+    let mut quoted = false;
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            '\n' if quoted => {
+                line += 1;
+                field.push(c);
+            }
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push((start, std::mem::take(&mut record)));
+                line += 1;
+                start = line;
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        let error = ImportError { line: start, message: "the quoted field is never closed".to_string() };
+        return Err(TodoError::InvalidImport(vec![error]));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((start, record));
+    }
+    // Blank lines are skipped
+    records.retain(|(_, record)| record.len() > 1 || record[0].trim() != "");
+    Ok(records)
+}
+
+/// The rows of a CSV import, its header names the `text` column and the optional `completed` one.
+fn csv_todos(body: &str) -> Result<Vec<(usize, Result<CreateTodo, String>)>, TodoError> {
+    let mut records = csv_records(body)?.into_iter();
+    let header = match records.next() {
+        Some((_, header)) => header,
+        None => return Ok(Vec::new()),
+    };
+    let column = |name: &str| header.iter().position(|column| column.trim().eq_ignore_ascii_case(name));
+    let (text, completed) = match column("text") {
+        Some(text) => (text, column("completed")),
+        None => {
+            let error = ImportError { line: 1, message: "the header has no text column".to_string() };
+            return Err(TodoError::InvalidImport(vec![error]));
+        }
+    };
+    Ok(records.map(|(line, record)| {
+        let todo = match record.get(text) {
+            None => Err("the text column is missing".to_string()),
+            Some(text) => {
+                let completed = completed.and_then(|completed| record.get(completed)).map_or("", |completed| completed.trim());
+                match completed.to_lowercase().as_str() {
+                    "" | "false" | "0" => Ok(CreateTodo { text: text.clone(), completed: false }),
+                    "true" | "1" => Ok(CreateTodo { text: text.clone(), completed: true }),
+                    _ => Err(format!("completed must be true or false, not {}", completed)),
+                }
+            }
+        };
+        (line, todo)
+    }).collect())
+}
+
+/// A `- [ ] text` or `- [x] text` item, `*` and `+` bullets are also accepted.
+fn markdown_todo(line: &str) -> Result<CreateTodo, String> {
+    let invalid = || "not a task list item, expected - [ ] text or - [x] text".to_string();
+    let item = line.trim_start()
+        .strip_prefix(|c: char| c == '-' || c == '*' || c == '+')
+        .filter(|item| item.starts_with(' '))
+        .ok_or_else(invalid)?
+        .trim_start();
+    let (completed, text) = if let Some(text) = item.strip_prefix("[ ]") {
+        (false, text)
+    } else if let Some(text) = item.strip_prefix("[x]").or_else(|| item.strip_prefix("[X]")) {
+        (true, text)
+    } else {
+        return Err(invalid());
+    };
+    Ok(CreateTodo { text: text.trim().to_string(), completed })
+}
+
+/// A line of an import which can't be imported.
+#[derive(Debug, Serialize)]
+pub struct ImportError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Page size used when the client doesn't ask for one.
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+/// Bigger `limit` are silently lowered to this value.
+pub const MAX_PAGE_SIZE: i64 = 100;
+/// Longest accepted search text.
+pub const MAX_QUERY_LEN: usize = 200;
+
+/// The supported orders of a list, every order ends with the id so it is total.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Id,
+    IdDesc,
+    Text,
+    Completed,
+}
+
+impl SortOrder {
+    pub fn parse(sort: &str) -> Result<Self, TodoError> {
+        match sort {
+            "id" => Ok(SortOrder::Id),
+            "-id" => Ok(SortOrder::IdDesc),
+            "text" => Ok(SortOrder::Text),
+            "completed" => Ok(SortOrder::Completed),
+            _ => Err(TodoError::Validation(format!("unknown sort order {}, expected id, -id, text or completed", sort))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Id => "id",
+            SortOrder::IdDesc => "-id",
+            SortOrder::Text => "text",
+            SortOrder::Completed => "completed",
+        }
+    }
+
+    pub fn compare(&self, a: &Todo, b: &Todo) -> Ordering {
+        match self {
+            SortOrder::Id => a.id.cmp(&b.id),
+            SortOrder::IdDesc => b.id.cmp(&a.id),
+            SortOrder::Text => (&a.text, a.id).cmp(&(&b.text, b.id)),
+            SortOrder::Completed => (a.completed, a.id).cmp(&(b.completed, b.id)),
+        }
+    }
+}
+
+/// The validated filters of a list.
+#[derive(Debug, Clone)]
+pub struct TodoFilter {
+    pub completed: Option<bool>,
+    pub q: Option<String>,
+    pub sort: SortOrder,
+    pub scope: Scope,
+}
+
+impl TodoFilter {
+    pub fn matches(&self, todo: &Todo) -> bool {
+        let completed = self.completed.map_or(true, |completed| todo.completed == completed);
+        let text = self.q.as_ref()
+            .map_or(true, |q| todo.text.to_lowercase().contains(&q.to_lowercase()));
+        completed && text && todo.deleted_at.is_none() && self.scope.allows(todo)
+    }
+
+    /// The ILIKE pattern of `q`, its wildcards are escaped to match a plain substring.
+    pub fn pattern(&self) -> Option<String> {
+        self.q.as_ref().map(|q| {
+            format!("%{}%", q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+        })
+    }
+}
+
+/// One page of a list, with what is needed to fetch the other pages.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+/// One page of a keyset paging, `next` is the cursor of the following page if any.
+#[derive(Debug, Serialize)]
+pub struct CursorPage<T> {
+    pub items: Vec<T>,
+    pub limit: i64,
+    pub next: Option<String>,
+}
+
+/// A slice of a keyset paged list, each todo with its cursor, as the Relay connections want it.
+pub struct Window<T> {
+    pub edges: Vec<(String, T)>,
+    pub has_previous: bool,
+    pub has_next: bool,
+}
+
+/// The sort key of the last todo seen, handed to the client as opaque base64.
+/// `sort` tells which sort order the key belongs to, only the fields of that order are set.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Cursor {
+    pub sort: String,
+    pub id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed: Option<bool>,
+}
+
+impl Cursor {
+    pub fn after(todo: &Todo, sort: SortOrder) -> Self {
+        Cursor {
+            sort: sort.as_str().to_string(),
+            id: todo.id,
+            text: if sort == SortOrder::Text { Some(todo.text.clone()) } else { None },
+            completed: if sort == SortOrder::Completed { Some(todo.completed) } else { None },
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        // Serializing a struct of strings, booleans and integers can't fail
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        base64::encode_config(&json, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Decode `cursor` and check that it is a key of the `sort` order.
+    pub fn decode(cursor: &str, sort: SortOrder) -> Result<Self, TodoError> {
+        let invalid = || TodoError::InvalidCursor(cursor.to_string());
+        let json = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        let cursor: Cursor = serde_json::from_slice(&json).map_err(|_| invalid())?;
+        let complete = match sort {
+            SortOrder::Id | SortOrder::IdDesc => cursor.text.is_none() && cursor.completed.is_none(),
+            SortOrder::Text => cursor.text.is_some() && cursor.completed.is_none(),
+            SortOrder::Completed => cursor.text.is_none() && cursor.completed.is_some(),
+        };
+        if cursor.sort != sort.as_str() || !complete || cursor.id < 0 {
+            return Err(invalid());
+        }
+        Ok(cursor)
+    }
+
+    /// A todo carrying the sort key, to compare it with `SortOrder::compare`.
+    pub fn as_todo(&self) -> Todo {
+        Todo {
+            id: self.id,
+            text: self.text.clone().unwrap_or_default(),
+            completed: self.completed.unwrap_or_default(),
+            version: 0,
+            owner_id: String::new(),
+            deleted_at: None,
+        }
+    }
+}
+
+/// Reading the todos.
+pub const READ_SCOPE: &str = "todos:read";
+/// Creating, changing and deleting the todos.
+pub const WRITE_SCOPE: &str = "todos:write";
+/// The scopes an API key can be given.
+pub const API_KEY_SCOPES: [&str; 2] = [READ_SCOPE, WRITE_SCOPE];
+
+/// A stored API key, only a salted hash of the secret is kept.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKey {
+    pub id: i32,
+    /// The public part of the key, used to find it back
+    pub prefix: String,
+    #[serde(skip_serializing)]
+    pub salt: String,
+    #[serde(skip_serializing)]
+    pub hash: String,
+    pub name: String,
+    /// The admin who created the key, the key acts on their behalf
+    pub owner_id: String,
+    /// Space separated scopes
+    pub scopes: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+// The JSON body of POST /api-keys.
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+/// The answer to POST /api-keys, the only time the key is shown.
+#[derive(Debug, Serialize)]
+pub struct ApiKeyCreated {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+/// Everything that can go wrong in the model layer.
+#[derive(Debug)]
+pub enum TodoError {
+    /// No todo with this id.
+    NotFound(i32),
+    /// No API key with this id.
+    ApiKeyNotFound(i32),
+    /// The todo clashes with an existing one.
+    Conflict(String),
+    /// The write was made on an old version of the todo.
+    StaleVersion { id: i32, expected: i32, current: i32 },
+    /// The input was refused before reaching the storage.
+    Validation(String),
+    /// The storage could not be reached or failed to answer.
+    StorageUnavailable(String),
+    /// The paging cursor was not produced by this server.
+    InvalidCursor(String),
+    /// Some lines of an import are invalid, nothing was imported.
+    InvalidImport(Vec<ImportError>),
+}
+
+impl TodoError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            TodoError::NotFound(_) | TodoError::ApiKeyNotFound(_) => StatusCode::NOT_FOUND,
+            TodoError::Conflict(_) => StatusCode::CONFLICT,
+            TodoError::StaleVersion { .. } => StatusCode::CONFLICT,
+            TodoError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            TodoError::StorageUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            TodoError::InvalidCursor(_) => StatusCode::BAD_REQUEST,
+            TodoError::InvalidImport(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    /// Machine readable details of the error, for the clients to recover from it.
+    pub fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            TodoError::StaleVersion { id, expected, current } => Some(serde_json::json!({
+                "id": id,
+                "expected_version": expected,
+                "current_version": current,
+            })),
+            TodoError::InvalidImport(errors) => Some(serde_json::json!({ "errors": errors })),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TodoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TodoError::NotFound(id) => write!(f, "NOT_FOUND: no todo with id {}", id),
+            TodoError::ApiKeyNotFound(id) => write!(f, "NOT_FOUND: no API key with id {}", id),
+            TodoError::Conflict(reason) => write!(f, "CONFLICT: {}", reason),
+            TodoError::StaleVersion { id, expected, current } => {
+                write!(f, "STALE_VERSION: todo {} is at version {}, not {}", id, current, expected)
+            }
+            TodoError::Validation(reason) => write!(f, "VALIDATION: {}", reason),
+            TodoError::StorageUnavailable(reason) => write!(f, "STORAGE_UNAVAILABLE: {}", reason),
+            TodoError::InvalidCursor(cursor) => write!(f, "INVALID_CURSOR: {}", cursor),
+            TodoError::InvalidImport(errors) => {
+                let first = errors.first().map_or(0, |error| error.line);
+                write!(f, "INVALID_IMPORT: {} invalid lines, the first one is line {}", errors.len(), first)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TodoError {}
+
+impl warp::reject::Reject for TodoError {}
+
+#[cfg(feature = "postgres")]
+impl From<sqlx::Error> for TodoError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            // 23505 is the Postgres code of unique_violation
+            sqlx::Error::Database(ref db_err) if db_err.code() == Some("23505") => {
+                TodoError::Conflict(db_err.message().to_string())
+            }
+            // the other integrity_constraint_violation codes (23xxx) come from bad input
+            sqlx::Error::Database(ref db_err) if db_err.code().map_or(false, |code| code.starts_with("23")) => {
+                TodoError::Validation(db_err.message().to_string())
+            }
+            err => TodoError::StorageUnavailable(err.to_string()),
+        }
+    }
+}
+
+/// The storage operations needed by the handlers.
+/// Todos outside of `scope` are reported as not found.
+#[async_trait]
+pub trait TodoRepository: Send + Sync {
+    async fn get_todo(&self, id: i32, scope: &Scope) -> Result<Todo, TodoError>;
+    /// The todos of `ids` in the scope, the missing ones are left out.
+    async fn get_todos(&self, ids: &[i32], scope: &Scope) -> Result<Vec<Todo>, TodoError>;
+    async fn list_todos(&self, filter: &TodoFilter, offset: i64, limit: i64) -> Result<Page<Todo>, TodoError>;
+    /// Up to `limit` todos coming after the `after` key in the `filter` order.
+    async fn list_todos_after(&self, filter: &TodoFilter, after: Option<&Cursor>, limit: i64) -> Result<Vec<Todo>, TodoError>;
+    /// Up to `limit` todos coming just before the `before` key, or ending the list, in the `filter` order.
+    async fn list_todos_before(&self, filter: &TodoFilter, before: Option<&Cursor>, limit: i64) -> Result<Vec<Todo>, TodoError>;
+    async fn count_todos(&self, filter: &TodoFilter) -> Result<i64, TodoError>;
+    /// Every write is recorded in the audit log, in the same transaction, on behalf of `actor`.
+    /// The todo belongs to its creator.
+    async fn create_todo(&self, text: String, completed: bool, actor: &str) -> Result<Todo, TodoError>;
+    /// Every write bumps the version of the todo, and is refused when
+    /// `version` is given and is not the current one.
+    async fn update_todo(&self, id: i32, text: String, completed: bool, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError>;
+    /// Update the fields which are `Some`, and return the updated todo.
+    async fn patch_todo(&self, id: i32, text: Option<String>, completed: Option<bool>, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError>;
+    /// Move the todo to the trash, and return it.
+    async fn delete_todo(&self, id: i32, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError>;
+    /// Move the completed todos of the scope to the trash, returns them.
+    async fn clear_completed(&self, actor: &str, scope: &Scope) -> Result<Vec<Todo>, TodoError>;
+    /// The todos of the scope in the trash, the last deleted first.
+    async fn list_trash(&self, scope: &Scope) -> Result<Vec<Todo>, TodoError>;
+    /// Take the todo out of the trash, this is a write which bumps its version.
+    async fn restore_todo(&self, id: i32, actor: &str, scope: &Scope) -> Result<Todo, TodoError>;
+    /// Delete for good the todos in the trash since before `deleted_before`, returns how many.
+    async fn purge_trash(&self, deleted_before: DateTime<Utc>) -> Result<u64, TodoError>;
+    /// Run the operations in a single transaction, the todos are created for `actor`.
+    /// With `atomic` the first failure rolls everything back and ends the results,
+    /// otherwise the failed operations are skipped and the others are written.
+    async fn bulk(&self, operations: Vec<BulkOperation>, atomic: bool, actor: &str, scope: &Scope) -> Result<Vec<Result<Todo, TodoError>>, TodoError>;
+    /// The audit entries of the todo, oldest first, empty when the todo is not in the scope.
+    async fn todo_history(&self, id: i32, scope: &Scope) -> Result<Vec<AuditEntry>, TodoError>;
+    /// Up to `limit` audit entries matching `opts`, newest first.
+    async fn list_audit(&self, opts: &AuditOptions, limit: i64) -> Result<Vec<AuditEntry>, TodoError>;
+    /// Every todo of the scope by id, read as the stream is polled.
+    async fn export_todos(&self, scope: &Scope) -> Result<TodoRows, TodoError>;
+
+    async fn create_api_key(&self, prefix: String, salt: String, hash: String, name: String, owner_id: String, scopes: String) -> Result<ApiKey, TodoError>;
+    /// The keys created by `owner_id`.
+    async fn list_api_keys(&self, owner_id: &str) -> Result<Vec<ApiKey>, TodoError>;
+    /// Revoked keys are kept, so the list still shows them. Only the owner can revoke a key.
+    async fn revoke_api_key(&self, id: i32, owner_id: &str) -> Result<(), TodoError>;
+    async fn find_api_key(&self, prefix: &str) -> Result<Option<ApiKey>, TodoError>;
+}
+
+/// Todos stored in the `todos` table of a Postgres database.
+#[cfg(feature = "postgres")]
+pub struct PgTodoRepository {
+    pool: PgPool,
+}
+
+#[cfg(feature = "postgres")]
+type PgTransaction = Transaction<PoolConnection<PgConnection>>;
+
+/// How many todos of an export are read at once, the connection goes back to the pool in between.
+#[cfg(feature = "postgres")]
+const EXPORT_CHUNK: i64 = 500;
+
+#[cfg(feature = "postgres")]
+impl PgTodoRepository {
+    pub fn new(pool: PgPool) -> Self {
+        PgTodoRepository { pool }
+    }
+
+    /// Lock the todo `id` until the end of the transaction, when it is at `version` if one is given.
+    async fn lock_todo(tx: &mut PgTransaction, id: i32, version: Option<i32>, scope: &Scope) -> Result<Todo, TodoError> {
+        let todo = sqlx::query_as!(Todo,
+            "SELECT * FROM todos WHERE id = $1 AND ($2::TEXT IS NULL OR owner_id = $2) AND deleted_at IS NULL FOR UPDATE",
+            id, scope.owner())
+            .fetch_optional(&mut *tx).await?
+            .ok_or(TodoError::NotFound(id))?;
+        match version {
+            Some(expected) if expected != todo.version => Err(TodoError::StaleVersion { id, expected, current: todo.version }),
+            _ => Ok(todo),
+        }
+    }
+
+    /// Add the write to the audit log, inside its transaction.
+    async fn record(tx: &mut PgTransaction, actor: &str, action: AuditAction, before: Option<&Todo>, after: Option<&Todo>) -> Result<(), TodoError> {
+        let entry = AuditEntry::new(0, actor, action, before, after);
+        sqlx::query!(
+            "INSERT INTO todo_events (todo_id, actor, action, before, after) VALUES($1, $2, $3, $4, $5)",
+            entry.todo_id, entry.actor, entry.action, entry.before, entry.after)
+            .execute(&mut *tx).await?;
+        Ok(())
+    }
+
+    /// One write and its audit entry, inside its transaction.
+    async fn apply(tx: &mut PgTransaction, operation: BulkOperation, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let (action, before, todo) = match operation {
+            BulkOperation::Create { text, completed } => {
+                let todo = sqlx::query_as!(Todo,
+                    "INSERT INTO todos (text, completed, owner_id) VALUES($1, $2, $3) RETURNING *",
+                    text, completed, actor)
+                    .fetch_one(&mut *tx).await?;
+                (AuditAction::Created, None, todo)
+            }
+            BulkOperation::Update { id, text, completed, version } => {
+                let before = Self::lock_todo(tx, id, version, scope).await?;
+                let todo = sqlx::query_as!(Todo,
+                    "UPDATE todos SET text = COALESCE($1, text), completed = COALESCE($2, completed), version = version + 1
+                    where id = $3 RETURNING *",
+                    text, completed, id)
+                    .fetch_one(&mut *tx).await?;
+                (AuditAction::Updated, Some(before), todo)
+            }
+            BulkOperation::Delete { id, version } => {
+                let before = Self::lock_todo(tx, id, version, scope).await?;
+                let todo = sqlx::query_as!(Todo,
+                    "UPDATE todos SET deleted_at = NOW(), version = version + 1 WHERE id = $1 RETURNING *",
+                    id)
+                    .fetch_one(&mut *tx).await?;
+                (AuditAction::Deleted, Some(before), todo)
+            }
+        };
+        Self::record(tx, actor, action, before.as_ref(), Some(&todo)).await?;
+        Ok(todo)
+    }
+
+    /// A single write in a transaction of its own.
+    async fn write(&self, operation: BulkOperation, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let mut tx = self.pool.begin().await?;
+        let todo = Self::apply(&mut tx, operation, actor, scope).await?;
+        tx.commit().await?;
+        Ok(todo)
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl TodoRepository for PgTodoRepository {
+    async fn get_todo(&self, id: i32, scope: &Scope) -> Result<Todo, TodoError> {
+        let todo = sqlx::query_as!(Todo, "SELECT * FROM todos WHERE id = $1 AND ($2::TEXT IS NULL OR owner_id = $2) AND deleted_at IS NULL", id, scope.owner())
+            .fetch_optional(&self.pool).await?;
+        todo.ok_or(TodoError::NotFound(id))
+    }
+
+    async fn get_todos(&self, ids: &[i32], scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+        let todos = sqlx::query_as!(Todo, "SELECT * FROM todos WHERE id = ANY($1) AND ($2::TEXT IS NULL OR owner_id = $2) AND deleted_at IS NULL", ids, scope.owner())
+            .fetch_all(&self.pool).await?;
+        Ok(todos)
+    }
+
+    async fn list_todos(&self, filter: &TodoFilter, offset: i64, limit: i64) -> Result<Page<Todo>, TodoError> {
+        let pattern = filter.pattern();
+        let owner = filter.scope.owner();
+        let todos_list = sqlx::query_as!(Todo,
+            "SELECT * FROM todos
+            WHERE ($1::BOOLEAN IS NULL OR completed = $1) AND ($2::TEXT IS NULL OR text ILIKE $2)
+            AND ($6::TEXT IS NULL OR owner_id = $6) AND deleted_at IS NULL
+            ORDER BY CASE WHEN $3::TEXT = 'text' THEN text END,
+                CASE WHEN $3::TEXT = 'completed' THEN completed END,
+                CASE WHEN $3::TEXT = '-id' THEN id END DESC,
+                id
+            OFFSET $4 LIMIT $5",
+            filter.completed, pattern, filter.sort.as_str(), offset, limit, owner)
+            .fetch_all(&self.pool).await?;
+        let total = self.count_todos(filter).await?;
+        Ok(Page { items: todos_list, total, offset, limit })
+    }
+
+    async fn list_todos_after(&self, filter: &TodoFilter, after: Option<&Cursor>, limit: i64) -> Result<Vec<Todo>, TodoError> {
+        let after_id = after.map(|cursor| cursor.id);
+        let after_text = after.and_then(|cursor| cursor.text.clone());
+        let after_completed = after.and_then(|cursor| cursor.completed);
+        let todos_list = sqlx::query_as!(Todo,
+            "SELECT * FROM todos
+            WHERE ($1::BOOLEAN IS NULL OR completed = $1) AND ($2::TEXT IS NULL OR text ILIKE $2)
+            AND ($8::TEXT IS NULL OR owner_id = $8) AND deleted_at IS NULL
+            AND ($4::INTEGER IS NULL
+                OR ($3::TEXT = 'id' AND id > $4)
+                OR ($3::TEXT = '-id' AND id < $4)
+                OR ($3::TEXT = 'text' AND (text, id) > ($5::TEXT, $4))
+                OR ($3::TEXT = 'completed' AND (completed, id) > ($6::BOOLEAN, $4)))
+            ORDER BY CASE WHEN $3::TEXT = 'text' THEN text END,
+                CASE WHEN $3::TEXT = 'completed' THEN completed END,
+                CASE WHEN $3::TEXT = '-id' THEN id END DESC,
+                id
+            LIMIT $7",
+            filter.completed, filter.pattern(), filter.sort.as_str(), after_id, after_text, after_completed, limit,
+            filter.scope.owner())
+            .fetch_all(&self.pool).await?;
+        Ok(todos_list)
+    }
+
+    async fn list_todos_before(&self, filter: &TodoFilter, before: Option<&Cursor>, limit: i64) -> Result<Vec<Todo>, TodoError> {
+        let before_id = before.map(|cursor| cursor.id);
+        let before_text = before.and_then(|cursor| cursor.text.clone());
+        let before_completed = before.and_then(|cursor| cursor.completed);
+        // Walk the order backward from the key, then turn the page around
+        let mut todos_list = sqlx::query_as!(Todo,
+            "SELECT * FROM todos
+            WHERE ($1::BOOLEAN IS NULL OR completed = $1) AND ($2::TEXT IS NULL OR text ILIKE $2)
+            AND ($8::TEXT IS NULL OR owner_id = $8) AND deleted_at IS NULL
+            AND ($4::INTEGER IS NULL
+                OR ($3::TEXT = 'id' AND id < $4)
+                OR ($3::TEXT = '-id' AND id > $4)
+                OR ($3::TEXT = 'text' AND (text, id) < ($5::TEXT, $4))
+                OR ($3::TEXT = 'completed' AND (completed, id) < ($6::BOOLEAN, $4)))
+            ORDER BY CASE WHEN $3::TEXT = 'text' THEN text END DESC,
+                CASE WHEN $3::TEXT = 'completed' THEN completed END DESC,
+                CASE WHEN $3::TEXT = '-id' THEN id END,
+                id DESC
+            LIMIT $7",
+            filter.completed, filter.pattern(), filter.sort.as_str(), before_id, before_text, before_completed, limit,
+            filter.scope.owner())
+            .fetch_all(&self.pool).await?;
+        todos_list.reverse();
+        Ok(todos_list)
+    }
+
+    async fn count_todos(&self, filter: &TodoFilter) -> Result<i64, TodoError> {
+        let count = sqlx::query!(
+            "SELECT COUNT(*) AS total FROM todos
+            WHERE ($1::BOOLEAN IS NULL OR completed = $1) AND ($2::TEXT IS NULL OR text ILIKE $2)
+            AND ($3::TEXT IS NULL OR owner_id = $3) AND deleted_at IS NULL",
+            filter.completed, filter.pattern(), filter.scope.owner())
+            .fetch_one(&self.pool).await?;
+        Ok(count.total)
+    }
+
+    async fn create_todo(&self, text: String, completed: bool, actor: &str) -> Result<Todo, TodoError> {
+        self.write(BulkOperation::Create { text, completed }, actor, &Scope::Everything).await
+    }
+
+    async fn update_todo(&self, id: i32, text: String, completed: bool, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        self.write(BulkOperation::Update { id, text: Some(text), completed: Some(completed), version }, actor, scope).await
+    }
+
+    async fn patch_todo(&self, id: i32, text: Option<String>, completed: Option<bool>, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        self.write(BulkOperation::Update { id, text, completed, version }, actor, scope).await
+    }
+
+    async fn delete_todo(&self, id: i32, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        self.write(BulkOperation::Delete { id, version }, actor, scope).await
+    }
+
+    async fn clear_completed(&self, actor: &str, scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+        let mut tx = self.pool.begin().await?;
+        let todos = sqlx::query_as!(Todo,
+            "UPDATE todos SET deleted_at = NOW(), version = version + 1
+            WHERE completed AND ($1::TEXT IS NULL OR owner_id = $1) AND deleted_at IS NULL
+            RETURNING *",
+            scope.owner())
+            .fetch_all(&mut tx).await?;
+        for todo in &todos {
+            // Only the trash date and the version moved
+            let before = Todo { deleted_at: None, version: todo.version - 1, ..todo.clone() };
+            Self::record(&mut tx, actor, AuditAction::Deleted, Some(&before), Some(todo)).await?;
+        }
+        tx.commit().await?;
+        Ok(todos)
+    }
+
+    async fn list_trash(&self, scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+        let todos = sqlx::query_as!(Todo,
+            "SELECT * FROM todos WHERE deleted_at IS NOT NULL AND ($1::TEXT IS NULL OR owner_id = $1)
+            ORDER BY deleted_at DESC, id",
+            scope.owner())
+            .fetch_all(&self.pool).await?;
+        Ok(todos)
+    }
+
+    async fn restore_todo(&self, id: i32, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let mut tx = self.pool.begin().await?;
+        let before = sqlx::query_as!(Todo,
+            "SELECT * FROM todos WHERE id = $1 AND ($2::TEXT IS NULL OR owner_id = $2) AND deleted_at IS NOT NULL FOR UPDATE",
+            id, scope.owner())
+            .fetch_optional(&mut tx).await?
+            .ok_or(TodoError::NotFound(id))?;
+        let todo = sqlx::query_as!(Todo,
+            "UPDATE todos SET deleted_at = NULL, version = version + 1 WHERE id = $1 RETURNING *",
+            id)
+            .fetch_one(&mut tx).await?;
+        Self::record(&mut tx, actor, AuditAction::Restored, Some(&before), Some(&todo)).await?;
+        tx.commit().await?;
+        Ok(todo)
+    }
+
+    // A single statement, so the purged todos and their audit entries go together
+    async fn purge_trash(&self, deleted_before: DateTime<Utc>) -> Result<u64, TodoError> {
+        let purged = sqlx::query!(
+            "WITH purged AS (DELETE FROM todos WHERE deleted_at < $1 RETURNING *)
+            INSERT INTO todo_events (todo_id, actor, action, before)
+            SELECT id, $2, $3, row_to_json(purged)::JSONB FROM purged",
+            deleted_before, SYSTEM_ACTOR, AuditAction::Purged.as_str())
+            .execute(&self.pool).await?;
+        Ok(purged)
+    }
+
+    async fn bulk(&self, operations: Vec<BulkOperation>, atomic: bool, actor: &str, scope: &Scope) -> Result<Vec<Result<Todo, TodoError>>, TodoError> {
+        let mut tx = self.pool.begin().await?;
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            if atomic {
+                let result = Self::apply(&mut tx, operation, actor, scope).await;
+                let failed = result.is_err();
+                results.push(result);
+                if failed {
+                    tx.rollback().await?;
+                    return Ok(results);
+                }
+            } else {
+                // A failed statement aborts the whole transaction, unless it is undone up to a savepoint
+                sqlx::query("SAVEPOINT bulk_operation").execute(&mut tx).await?;
+                let result = Self::apply(&mut tx, operation, actor, scope).await;
+                let savepoint = if result.is_ok() { "RELEASE SAVEPOINT bulk_operation" } else { "ROLLBACK TO SAVEPOINT bulk_operation" };
+                sqlx::query(savepoint).execute(&mut tx).await?;
+                results.push(result);
+            }
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+
+    // The owner of a todo never changes, any of its snapshots tells it
+    async fn todo_history(&self, id: i32, scope: &Scope) -> Result<Vec<AuditEntry>, TodoError> {
+        let history = sqlx::query_as!(AuditEntry,
+            "SELECT * FROM todo_events
+            WHERE todo_id = $1 AND ($2::TEXT IS NULL OR COALESCE(after, before)->>'owner_id' = $2)
+            ORDER BY id",
+            id, scope.owner())
+            .fetch_all(&self.pool).await?;
+        Ok(history)
+    }
+
+    async fn list_audit(&self, opts: &AuditOptions, limit: i64) -> Result<Vec<AuditEntry>, TodoError> {
+        let entries = sqlx::query_as!(AuditEntry,
+            "SELECT * FROM todo_events
+            WHERE ($1::TEXT IS NULL OR actor = $1) AND ($2::INTEGER IS NULL OR todo_id = $2)
+            AND ($3::TIMESTAMPTZ IS NULL OR created_at >= $3) AND ($4::TIMESTAMPTZ IS NULL OR created_at < $4)
+            AND ($5::INTEGER IS NULL OR id < $5)
+            ORDER BY id DESC
+            LIMIT $6",
+            opts.actor, opts.todo_id, opts.since, opts.until, opts.before, limit)
+            .fetch_all(&self.pool).await?;
+        Ok(entries)
+    }
+
+    async fn export_todos(&self, scope: &Scope) -> Result<TodoRows, TodoError> {
+        let pool = self.pool.clone();
+        let owner = scope.owner().map(str::to_string);
+        // Keyset paging on the id rather than a single `fetch` stream. The stream holds one pooled
+        // connection, and its open cursor, for as long as the slowest client takes to download, so a
+        // few slow exports would drain the pool of every other route. Chunks keep the memory just as
+        // flat: the next one is only read once the client took the previous one, and the connection
+        // goes back to the pool in between. A write during the export is seen or not depending on its
+        // id, which a single snapshot would avoid, but an export is not a backup.
+        let chunks = stream::unfold(Some(0), move |after: Option<i32>| {
+            let pool = pool.clone();
+            let owner = owner.clone();
+            async move {
+                let after = after?;
+                let chunk = sqlx::query_as!(Todo,
+                    "SELECT * FROM todos WHERE id > $1 AND ($2::TEXT IS NULL OR owner_id = $2) AND deleted_at IS NULL
+                    ORDER BY id
+                    LIMIT $3",
+                    after, owner, EXPORT_CHUNK)
+                    .fetch_all(&pool).await;
+                let (todos, next): (Vec<Result<Todo, TodoError>>, Option<i32>) = match chunk {
+                    // A short chunk is the last one
+                    Ok(todos) => {
+                        let next = todos.last().map(|todo| todo.id).filter(|_| todos.len() as i64 == EXPORT_CHUNK);
+                        (todos.into_iter().map(Ok).collect(), next)
+                    }
+                    Err(e) => (vec![Err(TodoError::from(e))], None),
+                };
+                Some((stream::iter(todos), next))
+            }
+        });
+        Ok(Box::pin(chunks.flatten()))
+    }
+
+    async fn create_api_key(&self, prefix: String, salt: String, hash: String, name: String, owner_id: String, scopes: String) -> Result<ApiKey, TodoError> {
+        let api_key = sqlx::query_as!(ApiKey,
+            "INSERT INTO api_keys (prefix, salt, hash, name, owner_id, scopes) VALUES($1, $2, $3, $4, $5, $6) RETURNING *",
+            prefix, salt, hash, name, owner_id, scopes)
+            .fetch_one(&self.pool).await?;
+        Ok(api_key)
+    }
+
+    async fn list_api_keys(&self, owner_id: &str) -> Result<Vec<ApiKey>, TodoError> {
+        let api_keys = sqlx::query_as!(ApiKey, "SELECT * FROM api_keys WHERE owner_id = $1 ORDER BY id", owner_id)
+            .fetch_all(&self.pool).await?;
+        Ok(api_keys)
+    }
+
+    async fn revoke_api_key(&self, id: i32, owner_id: &str) -> Result<(), TodoError> {
+        let rows = sqlx::query!("UPDATE api_keys SET revoked_at = COALESCE(revoked_at, NOW()) WHERE id = $1 AND owner_id = $2", id, owner_id)
+            .execute(&self.pool).await?;
+        if rows == 0 {
+            return Err(TodoError::ApiKeyNotFound(id));
+        }
+        Ok(())
+    }
+
+    async fn find_api_key(&self, prefix: &str) -> Result<Option<ApiKey>, TodoError> {
+        let api_key = sqlx::query_as!(ApiKey, "SELECT * FROM api_keys WHERE prefix = $1", prefix)
+            .fetch_optional(&self.pool).await?;
+        Ok(api_key)
+    }
+}
+
+/// Todos kept in memory, lost when the server stops. Handy to run the server without Postgres.
+#[derive(Default)]
+pub struct MemoryTodoRepository {
+    table: Mutex<MemoryTable>,
+}
+
+#[derive(Default)]
+struct MemoryTable {
+    todos: Vec<Todo>,
+    // Plays the role of the SERIAL sequence, ids are never reused
+    last_id: i32,
+    api_keys: Vec<ApiKey>,
+    last_api_key_id: i32,
+    audit: Vec<AuditEntry>,
+}
+
+impl MemoryTodoRepository {
+    pub fn new() -> Self {
+        MemoryTodoRepository::default()
+    }
+}
+
+impl MemoryTable {
+    /// The todo `id` if it is in `scope`, when it is at `version` if one is given.
+    fn find_version(&mut self, id: i32, version: Option<i32>, scope: &Scope) -> Result<&mut Todo, TodoError> {
+        let todo = self.todos.iter_mut()
+            .find(|todo| todo.id == id && todo.deleted_at.is_none() && scope.allows(todo))
+            .ok_or(TodoError::NotFound(id))?;
+        match version {
+            Some(expected) if expected != todo.version => {
+                Err(TodoError::StaleVersion { id, expected, current: todo.version })
+            }
+            _ => Ok(todo),
+        }
+    }
+
+    /// Add the write to the audit log, the entries are numbered like a SERIAL column.
+    fn record(&mut self, actor: &str, action: AuditAction, before: Option<&Todo>, after: Option<&Todo>) {
+        let id = self.audit.len() as i32 + 1;
+        self.audit.push(AuditEntry::new(id, actor, action, before, after));
+    }
+
+    /// One write and its audit entry.
+    fn apply(&mut self, operation: BulkOperation, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let (action, before, todo) = match operation {
+            BulkOperation::Create { text, completed } => {
+                self.last_id += 1;
+                let todo = Todo { id: self.last_id, text, completed, version: 1, owner_id: actor.to_string(), deleted_at: None };
+                self.todos.push(todo.clone());
+                (AuditAction::Created, None, todo)
+            }
+            BulkOperation::Update { id, text, completed, version } => {
+                let todo = self.find_version(id, version, scope)?;
+                let before = todo.clone();
+                if let Some(text) = text {
+                    todo.text = text;
+                }
+                if let Some(completed) = completed {
+                    todo.completed = completed;
+                }
+                todo.version += 1;
+                (AuditAction::Updated, Some(before), todo.clone())
+            }
+            BulkOperation::Delete { id, version } => {
+                let todo = self.find_version(id, version, scope)?;
+                let before = todo.clone();
+                todo.deleted_at = Some(Utc::now());
+                todo.version += 1;
+                (AuditAction::Deleted, Some(before), todo.clone())
+            }
+        };
+        self.record(actor, action, before.as_ref(), Some(&todo));
+        Ok(todo)
+    }
+
+    /// The todos matching `filter`, in its order.
+    fn sorted(&self, filter: &TodoFilter) -> Vec<Todo> {
+        let mut todos: Vec<Todo> = self.todos.iter()
+            .filter(|todo| filter.matches(todo))
+            .cloned()
+            .collect();
+        todos.sort_by(|a, b| filter.sort.compare(a, b));
+        todos
+    }
+}
+
+#[async_trait]
+impl TodoRepository for MemoryTodoRepository {
+    async fn get_todo(&self, id: i32, scope: &Scope) -> Result<Todo, TodoError> {
+        let table = self.table.lock().await;
+        table.todos.iter()
+            .find(|todo| todo.id == id && todo.deleted_at.is_none() && scope.allows(todo))
+            .cloned()
+            .ok_or(TodoError::NotFound(id))
+    }
+
+    async fn get_todos(&self, ids: &[i32], scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+        let table = self.table.lock().await;
+        Ok(table.todos.iter()
+            .filter(|todo| ids.contains(&todo.id) && todo.deleted_at.is_none() && scope.allows(todo))
+            .cloned()
+            .collect())
+    }
+
+    async fn list_todos(&self, filter: &TodoFilter, offset: i64, limit: i64) -> Result<Page<Todo>, TodoError> {
+        let table = self.table.lock().await;
+        let todos = table.sorted(filter);
+        let total = todos.len() as i64;
+        let items = todos.into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect();
+        Ok(Page { items, total, offset, limit })
+    }
+
+    async fn list_todos_after(&self, filter: &TodoFilter, after: Option<&Cursor>, limit: i64) -> Result<Vec<Todo>, TodoError> {
+        let table = self.table.lock().await;
+        let after = after.map(Cursor::as_todo);
+        Ok(table.sorted(filter).into_iter()
+            .filter(|todo| after.as_ref().map_or(true, |after| filter.sort.compare(todo, after) == Ordering::Greater))
+            .take(limit as usize)
+            .collect())
+    }
+
+    async fn list_todos_before(&self, filter: &TodoFilter, before: Option<&Cursor>, limit: i64) -> Result<Vec<Todo>, TodoError> {
+        let table = self.table.lock().await;
+        let before = before.map(Cursor::as_todo);
+        let todos: Vec<Todo> = table.sorted(filter).into_iter()
+            .filter(|todo| before.as_ref().map_or(true, |before| filter.sort.compare(todo, before) == Ordering::Less))
+            .collect();
+        let start = todos.len().saturating_sub(limit as usize);
+        Ok(todos[start..].to_vec())
+    }
+
+    async fn count_todos(&self, filter: &TodoFilter) -> Result<i64, TodoError> {
+        let table = self.table.lock().await;
+        Ok(table.todos.iter().filter(|todo| filter.matches(todo)).count() as i64)
+    }
+
+    async fn create_todo(&self, text: String, completed: bool, actor: &str) -> Result<Todo, TodoError> {
+        let mut table = self.table.lock().await;
+        table.apply(BulkOperation::Create { text, completed }, actor, &Scope::Everything)
+    }
+
+    async fn update_todo(&self, id: i32, text: String, completed: bool, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let mut table = self.table.lock().await;
+        table.apply(BulkOperation::Update { id, text: Some(text), completed: Some(completed), version }, actor, scope)
+    }
+
+    async fn patch_todo(&self, id: i32, text: Option<String>, completed: Option<bool>, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let mut table = self.table.lock().await;
+        table.apply(BulkOperation::Update { id, text, completed, version }, actor, scope)
+    }
+
+    async fn delete_todo(&self, id: i32, version: Option<i32>, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let mut table = self.table.lock().await;
+        table.apply(BulkOperation::Delete { id, version }, actor, scope)
+    }
+
+    async fn clear_completed(&self, actor: &str, scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+        let mut table = self.table.lock().await;
+        let cleared: Vec<BulkOperation> = table.todos.iter()
+            .filter(|todo| todo.completed && todo.deleted_at.is_none() && scope.allows(todo))
+            .map(|todo| BulkOperation::Delete { id: todo.id, version: None })
+            .collect();
+        cleared.into_iter()
+            .map(|operation| table.apply(operation, actor, scope))
+            .collect()
+    }
+
+    async fn list_trash(&self, scope: &Scope) -> Result<Vec<Todo>, TodoError> {
+        let table = self.table.lock().await;
+        let mut todos: Vec<Todo> = table.todos.iter()
+            .filter(|todo| todo.deleted_at.is_some() && scope.allows(todo))
+            .cloned()
+            .collect();
+        todos.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at).then(a.id.cmp(&b.id)));
+        Ok(todos)
+    }
+
+    async fn restore_todo(&self, id: i32, actor: &str, scope: &Scope) -> Result<Todo, TodoError> {
+        let mut table = self.table.lock().await;
+        let todo = table.todos.iter_mut()
+            .find(|todo| todo.id == id && todo.deleted_at.is_some() && scope.allows(todo))
+            .ok_or(TodoError::NotFound(id))?;
+        let before = todo.clone();
+        todo.deleted_at = None;
+        todo.version += 1;
+        let todo = todo.clone();
+        table.record(actor, AuditAction::Restored, Some(&before), Some(&todo));
+        Ok(todo)
+    }
+
+    async fn purge_trash(&self, deleted_before: DateTime<Utc>) -> Result<u64, TodoError> {
+        let mut table = self.table.lock().await;
+        let (purged, kept): (Vec<Todo>, Vec<Todo>) = table.todos.drain(..)
+            .partition(|todo| todo.deleted_at.map_or(false, |deleted_at| deleted_at < deleted_before));
+        table.todos = kept;
+        for todo in &purged {
+            table.record(SYSTEM_ACTOR, AuditAction::Purged, Some(todo), None);
+        }
+        Ok(purged.len() as u64)
+    }
+
+    async fn bulk(&self, operations: Vec<BulkOperation>, atomic: bool, actor: &str, scope: &Scope) -> Result<Vec<Result<Todo, TodoError>>, TodoError> {
+        let mut table = self.table.lock().await;
+        // The lock is held all along, the todos are put back to roll back
+        let (todos, last_id, audit) = (table.todos.clone(), table.last_id, table.audit.len());
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let result = table.apply(operation, actor, scope);
+            let failed = result.is_err();
+            results.push(result);
+            if atomic && failed {
+                table.todos = todos;
+                table.last_id = last_id;
+                table.audit.truncate(audit);
+                break;
+            }
+        }
+        Ok(results)
+    }
+
+    async fn todo_history(&self, id: i32, scope: &Scope) -> Result<Vec<AuditEntry>, TodoError> {
+        let table = self.table.lock().await;
+        let owner = |entry: &AuditEntry| entry.after.as_ref().or_else(|| entry.before.as_ref())
+            .and_then(|todo| todo.get("owner_id"))
+            .and_then(|owner| owner.as_str())
+            .map(str::to_string);
+        Ok(table.audit.iter()
+            .filter(|entry| entry.todo_id == id && scope.owner().map_or(true, |scope| owner(entry).as_deref() == Some(scope)))
+            .cloned()
+            .collect())
+    }
+
+    async fn list_audit(&self, opts: &AuditOptions, limit: i64) -> Result<Vec<AuditEntry>, TodoError> {
+        let table = self.table.lock().await;
+        Ok(table.audit.iter().rev()
+            .filter(|entry| opts.matches(entry))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn export_todos(&self, scope: &Scope) -> Result<TodoRows, TodoError> {
+        let table = self.table.lock().await;
+        let mut todos: Vec<Todo> = table.todos.iter()
+            .filter(|todo| todo.deleted_at.is_none() && scope.allows(todo))
+            .cloned()
+            .collect();
+        todos.sort_by_key(|todo| todo.id);
+        Ok(Box::pin(stream::iter(todos.into_iter().map(Ok))))
+    }
+
+    async fn create_api_key(&self, prefix: String, salt: String, hash: String, name: String, owner_id: String, scopes: String) -> Result<ApiKey, TodoError> {
+        let mut table = self.table.lock().await;
+        table.last_api_key_id += 1;
+        let api_key = ApiKey {
+            id: table.last_api_key_id,
+            prefix,
+            salt,
+            hash,
+            name,
+            owner_id,
+            scopes,
+            created_at: Utc::now(),
+            revoked_at: None,
+        };
+        table.api_keys.push(api_key.clone());
+        Ok(api_key)
+    }
+
+    async fn list_api_keys(&self, owner_id: &str) -> Result<Vec<ApiKey>, TodoError> {
+        let table = self.table.lock().await;
+        Ok(table.api_keys.iter().filter(|api_key| api_key.owner_id == owner_id).cloned().collect())
+    }
+
+    async fn revoke_api_key(&self, id: i32, owner_id: &str) -> Result<(), TodoError> {
+        let mut table = self.table.lock().await;
+        let api_key = table.api_keys.iter_mut()
+            .find(|api_key| api_key.id == id && api_key.owner_id == owner_id)
+            .ok_or(TodoError::ApiKeyNotFound(id))?;
+        api_key.revoked_at = api_key.revoked_at.or_else(|| Some(Utc::now()));
+        Ok(())
+    }
+
+    async fn find_api_key(&self, prefix: &str) -> Result<Option<ApiKey>, TodoError> {
+        let table = self.table.lock().await;
+        Ok(table.api_keys.iter().find(|api_key| api_key.prefix == prefix).cloned())
+    }
+}
+
+/// Where the todos are kept.
+pub enum Storage {
+    Memory,
+    /// The url of the Postgres database
+    #[cfg(feature = "postgres")]
+    Postgres(String),
+}
+
+/// Pick the storage backend: `TODO_STORAGE=memory` runs without any database,
+/// otherwise we connect to the Postgres database at `DATABASE_URL`.
+pub fn storage() -> Result<Storage, TodoError> {
+    dotenv::dotenv().ok();
+    if env::var("TODO_STORAGE").map(|storage| storage == "memory").unwrap_or(false) {
+        return Ok(Storage::Memory);
+    }
+    postgres_storage()
+}
+
+#[cfg(feature = "postgres")]
+fn postgres_storage() -> Result<Storage, TodoError> {
+    let url = env::var("DATABASE_URL")
+        .map_err(|_| TodoError::StorageUnavailable("DATABASE_URL is not set".to_string()))?;
+    Ok(Storage::Postgres(url))
+}
+
+#[cfg(not(feature = "postgres"))]
+fn postgres_storage() -> Result<Storage, TodoError> {
+    Err(TodoError::StorageUnavailable("built without the postgres feature, set TODO_STORAGE=memory".to_string()))
+}
+
+/// How long the todos stay in the trash, `TRASH_RETENTION_DAYS` or 30 days.
+pub fn trash_retention() -> Result<chrono::Duration, TodoError> {
+    match env::var("TRASH_RETENTION_DAYS") {
+        Err(_) => Ok(chrono::Duration::days(DEFAULT_RETENTION_DAYS)),
+        Ok(days) => match days.parse::<i64>() {
+            Ok(days) if days >= 0 => Ok(chrono::Duration::days(days)),
+            _ => Err(TodoError::Validation(format!("TRASH_RETENTION_DAYS must be a number of days, not {}", days))),
+        },
+    }
+}
+
+pub async fn blank_db(storage: &Storage) -> Result<Db, TodoError> {
+    match storage {
+        Storage::Memory => Ok(Arc::new(MemoryTodoRepository::new())),
+        #[cfg(feature = "postgres")]
+        Storage::Postgres(url) => {
+            //let pool = SqlitePool::new("sqlite:///Users/akersof/CLionProjects/warp-tutorial/todos.db").await.unwrap();
+            let pool = PgPool::builder().max_size(10).build(url).await?;
+            Ok(Arc::new(PgTodoRepository::new(pool)))
+        }
+    }
+}
+
+fn validate_text(text: &str) -> Result<(), TodoError> {
+    if text.trim().is_empty() {
+        return Err(TodoError::Validation("text must not be empty".to_string()));
+    }
+    Ok(())
+}
+
+fn page_size(opts: &ListOptions) -> Result<i64, TodoError> {
+    let limit = opts.limit.map_or(DEFAULT_PAGE_SIZE, i64::from);
+    if limit < 1 {
+        return Err(TodoError::Validation("limit must be at least 1".to_string()));
+    }
+    Ok(limit.min(MAX_PAGE_SIZE))
+}
+
+fn list_filter(opts: &ListOptions, scope: &Scope) -> Result<TodoFilter, TodoError> {
+    let sort = SortOrder::parse(opts.sort.as_deref().unwrap_or("id"))?;
+    let q = opts.q.as_ref().filter(|q| !q.is_empty()).cloned();
+    if q.as_ref().map_or(false, |q| q.chars().count() > MAX_QUERY_LEN) {
+        return Err(TodoError::Validation(format!("q must be at most {} characters", MAX_QUERY_LEN)));
+    }
+    Ok(TodoFilter { completed: opts.completed, q, sort, scope: scope.clone() })
+}
+
+// Here perform various known request, they will be called by the corresponding handler
+pub async fn db_get_todo(id: i32, scope: &Scope, db: &Db) -> Result<Todo, TodoError> {
+    db.get_todo(id, scope).await
+}
+
+pub async fn db_get_todos(ids: &[i32], scope: &Scope, db: &Db) -> Result<Vec<Todo>, TodoError> {
+    db.get_todos(ids, scope).await
+}
+
+pub async fn db_list_todos(opts: &ListOptions, scope: &Scope, db: &Db) -> Result<Page<Todo>, TodoError> {
+    let offset = opts.offset.map_or(0, i64::from);
+    if offset < 0 {
+        return Err(TodoError::Validation("offset must be positive".to_string()));
+    }
+    db.list_todos(&list_filter(opts, scope)?, offset, page_size(opts)?).await
+}
+
+pub async fn db_list_todos_after(opts: &ListOptions, scope: &Scope, db: &Db) -> Result<CursorPage<Todo>, TodoError> {
+    if opts.offset.is_some() {
+        return Err(TodoError::Validation("offset and after can't be used together".to_string()));
+    }
+    let filter = list_filter(opts, scope)?;
+    let limit = page_size(opts)?;
+    let after = match opts.after.as_deref() {
+        None | Some("") => None,
+        Some(cursor) => Some(Cursor::decode(cursor, filter.sort)?),
+    };
+    // One extra todo tells if there is a next page
+    let mut items = db.list_todos_after(&filter, after.as_ref(), limit + 1).await?;
+    let next = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        items.last().map(|todo| Cursor::after(todo, filter.sort).encode())
+    } else {
+        None
+    };
+    Ok(CursorPage { items, limit, next })
+}
+
+/// The todos after `after` and before `before`, the `first` ones or the `last` ones.
+/// The filters and the sort order come from `opts`.
+pub async fn db_list_todos_window(opts: &ListOptions, first: Option<i32>, after: Option<&str>, last: Option<i32>, before: Option<&str>, scope: &Scope, db: &Db) -> Result<Window<Todo>, TodoError> {
+    let filter = list_filter(opts, scope)?;
+    let decode = |cursor: Option<&str>| cursor.map(|cursor| Cursor::decode(cursor, filter.sort)).transpose();
+    let after = decode(after)?;
+    let before = decode(before)?;
+    let size = |size: Option<i32>| match size {
+        Some(size) if size < 0 => Err(TodoError::Validation("first and last must not be negative".to_string())),
+        size => Ok(size.map(|size| i64::from(size).min(MAX_PAGE_SIZE))),
+    };
+    let (first, last) = (size(first)?, size(last)?);
+    let is_after = |todo: &Todo| after.as_ref().map_or(true, |after| filter.sort.compare(todo, &after.as_todo()) == Ordering::Greater);
+    let is_before = |todo: &Todo| before.as_ref().map_or(true, |before| filter.sort.compare(todo, &before.as_todo()) == Ordering::Less);
+    // One extra todo tells if there is more in the direction of the paging
+    let (items, has_previous, has_next) = match (first, last) {
+        (Some(_), Some(_)) => return Err(TodoError::Validation("first and last can't be used together".to_string())),
+        (first, None) => {
+            let limit = first.unwrap_or(DEFAULT_PAGE_SIZE);
+            let mut items = db.list_todos_after(&filter, after.as_ref(), limit + 1).await?;
+            items.retain(|todo| is_before(todo));
+            let has_next = items.len() as i64 > limit;
+            items.truncate(limit as usize);
+            (items, after.is_some(), has_next)
+        }
+        (None, Some(last)) => {
+            let mut items = db.list_todos_before(&filter, before.as_ref(), last + 1).await?;
+            items.retain(|todo| is_after(todo));
+            let has_previous = items.len() as i64 > last;
+            if has_previous {
+                items.remove(0);
+            }
+            (items, has_previous, before.is_some())
+        }
+    };
+    let edges = items.into_iter()
+        .map(|todo| (Cursor::after(&todo, filter.sort).encode(), todo))
+        .collect();
+    Ok(Window { edges, has_previous, has_next })
+}
+
+pub async fn db_count_todos(opts: &ListOptions, scope: &Scope, db: &Db) -> Result<i64, TodoError> {
+    let filter = list_filter(opts, scope)?;
+    db.count_todos(&filter).await
+}
+
+pub async fn db_create_todos(text: String, completed: bool, actor: &str, db: &Db) -> Result<Todo, TodoError> {
+    validate_text(&text)?;
+    db.create_todo(text, completed, actor).await
+}
+
+pub async fn db_update_todo(id: i32, text: String, completed: bool, version: Option<i32>, actor: &str, scope: &Scope, db: &Db) -> Result<Todo, TodoError> {
+    validate_text(&text)?;
+    db.update_todo(id, text, completed, version, actor, scope).await
+}
+
+pub async fn db_patch_todo(id: i32, patch: UpdateTodo, actor: &str, scope: &Scope, db: &Db) -> Result<Todo, TodoError> {
+    if let Some(text) = &patch.text {
+        validate_text(text)?;
+    }
+    db.patch_todo(id, patch.text, patch.completed, patch.version, actor, scope).await
+}
+
+pub async fn db_delete_todo(id: i32, version: Option<i32>, actor: &str, scope: &Scope, db: &Db) -> Result<Todo, TodoError> {
+    db.delete_todo(id, version, actor, scope).await
+}
+
+pub async fn db_clear_completed(actor: &str, scope: &Scope, db: &Db) -> Result<Vec<Todo>, TodoError> {
+    db.clear_completed(actor, scope).await
+}
+
+pub async fn db_list_trash(scope: &Scope, db: &Db) -> Result<Vec<Todo>, TodoError> {
+    db.list_trash(scope).await
+}
+
+pub async fn db_restore_todo(id: i32, actor: &str, scope: &Scope, db: &Db) -> Result<Todo, TodoError> {
+    db.restore_todo(id, actor, scope).await
+}
+
+/// The history of a todo in the scope, a todo in the trash or purged still has one.
+pub async fn db_todo_history(id: i32, scope: &Scope, db: &Db) -> Result<Vec<AuditEntry>, TodoError> {
+    let history = db.todo_history(id, scope).await?;
+    if history.is_empty() {
+        // A todo written before the audit log has no history, but is still found
+        db.get_todo(id, scope).await?;
+    }
+    Ok(history)
+}
+
+pub async fn db_list_audit(opts: &AuditOptions, db: &Db) -> Result<Vec<AuditEntry>, TodoError> {
+    let limit = opts.limit.map_or(DEFAULT_PAGE_SIZE, i64::from);
+    if limit < 1 {
+        return Err(TodoError::Validation("limit must be at least 1".to_string()));
+    }
+    if let (Some(since), Some(until)) = (opts.since, opts.until) {
+        if since > until {
+            return Err(TodoError::Validation("since must not be after until".to_string()));
+        }
+    }
+    db.list_audit(opts, limit.min(MAX_PAGE_SIZE)).await
+}
+
+/// Purge the trash every `PURGE_INTERVAL`, for ever. A failed purge is retried the next time.
+pub async fn purge_trash(retention: chrono::Duration, db: Db) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match db.purge_trash(Utc::now() - retention).await {
+            Ok(0) => {}
+            Ok(purged) => println!("purged {} todos from the trash", purged),
+            Err(e) => eprintln!("cannot purge the trash: {}", e),
+        }
+    }
+}
+
+/// Run the operations of a bulk request, the invalid ones fail without reaching the storage.
+pub async fn db_bulk(request: BulkRequest, actor: &str, scope: &Scope, db: &Db) -> Result<BulkResponse, TodoError> {
+    let BulkRequest { mode, operations } = request;
+    if operations.len() > MAX_BULK_OPERATIONS {
+        return Err(TodoError::Validation(format!("at most {} operations are accepted", MAX_BULK_OPERATIONS)));
+    }
+    let atomic = mode == BulkMode::AllOrNothing;
+    let count = operations.len();
+    let mut outcomes: Vec<Option<Result<Todo, TodoError>>> = (0..count).map(|_| None).collect();
+    let statuses: Vec<StatusCode> = operations.iter()
+        .map(|operation| match operation {
+            BulkOperation::Create { .. } => StatusCode::CREATED,
+            _ => StatusCode::OK,
+        })
+        .collect();
+    let mut valid = Vec::with_capacity(count);
+    for (index, operation) in operations.into_iter().enumerate() {
+        let text = match &operation {
+            BulkOperation::Create { text, .. } => Some(text),
+            BulkOperation::Update { text, .. } => text.as_ref(),
+            BulkOperation::Delete { .. } => None,
+        };
+        match text.map_or(Ok(()), |text| validate_text(text)) {
+            Ok(()) => valid.push((index, operation)),
+            Err(e) => outcomes[index] = Some(Err(e)),
+        }
+    }
+    // With all or nothing, an invalid operation fails the request before any write
+    let committed = if atomic && valid.len() < count {
+        false
+    } else {
+        let (indexes, operations): (Vec<usize>, Vec<BulkOperation>) = valid.into_iter().unzip();
+        let results = db.bulk(operations, atomic, actor, scope).await?;
+        let committed = !atomic || results.iter().all(Result::is_ok);
+        for (index, result) in indexes.into_iter().zip(results) {
+            outcomes[index] = Some(result);
+        }
+        committed
+    };
+    let failed = outcomes.iter().position(|outcome| matches!(outcome, Some(Err(_))));
+    let results = outcomes.into_iter().enumerate().map(|(index, outcome)| {
+        match outcome {
+            Some(Ok(todo)) if committed => BulkResult { index, status: statuses[index].as_u16(), todo: Some(todo), error: None },
+            Some(Err(e)) => BulkResult { index, status: e.status().as_u16(), todo: None, error: Some(e.to_string()) },
+            // Rolled back or never run because of the failed operation
+            _ => BulkResult {
+                index,
+                status: StatusCode::FAILED_DEPENDENCY.as_u16(),
+                todo: None,
+                error: Some(format!("ROLLED_BACK: operation {} failed", failed.unwrap_or_default())),
+            },
+        }
+    }).collect();
+    Ok(BulkResponse { mode, committed, results })
+}
+
+pub async fn db_export_todos(scope: &Scope, db: &Db) -> Result<TodoRows, TodoError> {
+    db.export_todos(scope).await
+}
+
+/// Import every todo of `body` in a single transaction, or none when a line is invalid.
+pub async fn db_import_todos(format: TodoFormat, body: &str, actor: &str, scope: &Scope, db: &Db) -> Result<Vec<Todo>, TodoError> {
+    let operations = format.parse_todos(body)?.into_iter()
+        .map(|todo| BulkOperation::Create { text: todo.text, completed: todo.completed })
+        .collect();
+    db.bulk(operations, true, actor, scope).await?
+        .into_iter()
+        .collect()
+}
+
+pub async fn db_create_api_key(create: CreateApiKey, prefix: String, salt: String, hash: String, owner_id: String, db: &Db) -> Result<ApiKey, TodoError> {
+    validate_text(&create.name)?;
+    if create.scopes.is_empty() {
+        return Err(TodoError::Validation("an API key needs at least one scope".to_string()));
+    }
+    if let Some(scope) = create.scopes.iter().find(|scope| !API_KEY_SCOPES.contains(&scope.as_str())) {
+        return Err(TodoError::Validation(format!("unknown scope {}, expected one of {}", scope, API_KEY_SCOPES.join(", "))));
+    }
+    db.create_api_key(prefix, salt, hash, create.name, owner_id, create.scopes.join(" ")).await
+}
+
+pub async fn db_list_api_keys(owner_id: &str, db: &Db) -> Result<Vec<ApiKey>, TodoError> {
+    db.list_api_keys(owner_id).await
+}
+
+pub async fn db_revoke_api_key(id: i32, owner_id: &str, db: &Db) -> Result<(), TodoError> {
+    db.revoke_api_key(id, owner_id).await
+}
+
+pub async fn db_find_api_key(prefix: &str, db: &Db) -> Result<Option<ApiKey>, TodoError> {
+    db.find_api_key(prefix).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn import_errors(result: Result<Vec<CreateTodo>, TodoError>) -> Vec<(usize, String)> {
+        match result {
+            Err(TodoError::InvalidImport(errors)) => errors.into_iter().map(|e| (e.line, e.message)).collect(),
+            other => panic!("expected an invalid import, got {:?}", other),
+        }
+    }
+
+    fn texts(todos: &[CreateTodo]) -> Vec<(&str, bool)> {
+        todos.iter().map(|todo| (todo.text.as_str(), todo.completed)).collect()
+    }
+
+    #[test]
+    fn csv_quoted_fields() {
+        let body = "text,completed\n\"milk, eggs\",true\n\"two\nlines\",false\r\n\"say \"\"hi\"\"\",0\n";
+        let todos = TodoFormat::Csv.parse_todos(body).unwrap();
+        assert_eq!(texts(&todos), vec![("milk, eggs", true), ("two\nlines", false), ("say \"hi\"", false)]);
+    }
+
+    #[test]
+    fn csv_columns_in_any_order() {
+        let body = "id,Completed,text\n7,1,bread\n8,,butter";
+        let todos = TodoFormat::Csv.parse_todos(body).unwrap();
+        assert_eq!(texts(&todos), vec![("bread", true), ("butter", false)]);
+    }
+
+    #[test]
+    fn csv_errors_give_the_line_of_the_record() {
+        let body = "text,completed\nok,true\n\"multi\nline\",yes\n\n,false\nbad,maybe\n";
+        let errors = import_errors(TodoFormat::Csv.parse_todos(body));
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![3, 6, 7]);
+        assert_eq!(errors[0].1, "completed must be true or false, not yes");
+        assert_eq!(errors[2].1, "completed must be true or false, not maybe");
+    }
+
+    #[test]
+    fn csv_unclosed_quote() {
+        let errors = import_errors(TodoFormat::Csv.parse_todos("text\nfine\n\"never closed\nat all\n"));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 3);
+    }
+
+    #[test]
+    fn csv_without_text_column() {
+        let errors = import_errors(TodoFormat::Csv.parse_todos("name,completed\nmilk,true\n"));
+        assert_eq!(errors[0].0, 1);
+    }
+
+    #[test]
+    fn markdown_items() {
+        assert_eq!(markdown_todo("- [ ] milk").unwrap().text, "milk");
+        let todo = markdown_todo("  * [X]   spaced out  ").unwrap();
+        assert_eq!((todo.text.as_str(), todo.completed), ("spaced out", true));
+        assert!(markdown_todo("+ [x] bread").unwrap().completed);
+        assert!(markdown_todo("-[ ] no space").is_err());
+        assert!(markdown_todo("- [-] unknown box").is_err());
+        assert!(markdown_todo("plain text").is_err());
+    }
+
+    #[test]
+    fn markdown_errors_give_the_line() {
+        let body = "# Groceries\n\n- [ ] milk\n- [x] eggs\nnot a task\n- [ ]   \n";
+        let errors = import_errors(TodoFormat::Markdown.parse_todos(body));
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![5, 6]);
+    }
+
+    #[test]
+    fn json_lines_errors_give_the_line() {
+        let body = "{\"text\":\"milk\"}\n\n{\"completed\":true}\nnot json\n";
+        let errors = import_errors(TodoFormat::JsonLines.parse_todos(body));
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![3, 4]);
+    }
+
+    // What an export writes, an import reads back
+    #[tokio::test]
+    async fn export_import_round_trip() {
+        let db: Db = Arc::new(MemoryTodoRepository::new());
+        let texts = ["milk, eggs", "say \"hi\"", "two\nlines", "- [x] not a box"];
+        for (index, text) in texts.iter().enumerate() {
+            db_create_todos(text.to_string(), index % 2 == 0, "alice", &db).await.unwrap();
+        }
+        for format in &[TodoFormat::JsonLines, TodoFormat::Csv, TodoFormat::Markdown] {
+            let todos: Vec<Todo> = db_export_todos(&Scope::Everything, &db).await.unwrap()
+                .map(Result::unwrap)
+                .collect().await;
+            let body: String = format.header().into_iter().map(str::to_string)
+                .chain(todos.iter().map(|todo| format.line(todo)))
+                .collect();
+            let imported = format.parse_todos(&body).unwrap();
+            assert_eq!(imported.len(), texts.len(), "{}", format.extension());
+            for ((todo, text), index) in imported.iter().zip(&texts).zip(0..) {
+                // Markdown puts every todo on one line
+                let expected = match format {
+                    TodoFormat::Markdown => text.replace('\n', " "),
+                    _ => text.to_string(),
+                };
+                assert_eq!(todo.text, expected, "{}", format.extension());
+                assert_eq!(todo.completed, index % 2 == 0, "{}", format.extension());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn the_trash_is_purged_after_the_retention() {
+        let db = MemoryTodoRepository::new();
+        let scope = Scope::Everything;
+        for text in &["milk", "eggs", "bread"] {
+            db.create_todo(text.to_string(), false, "alice").await.unwrap();
+        }
+        db.delete_todo(1, None, "alice", &scope).await.unwrap();
+        db.delete_todo(2, None, "alice", &scope).await.unwrap();
+        // milk went to the trash before the retention, eggs just now
+        let retention = chrono::Duration::days(30);
+        for todo in db.table.lock().await.todos.iter_mut().filter(|todo| todo.id == 1) {
+            todo.deleted_at = Some(Utc::now() - chrono::Duration::days(31));
+        }
+
+        assert_eq!(db.purge_trash(Utc::now() - retention).await.unwrap(), 1);
+        let trash: Vec<i32> = db.list_trash(&scope).await.unwrap().iter().map(|todo| todo.id).collect();
+        assert_eq!(trash, vec![2]);
+        assert_eq!(db.get_todo(3, &scope).await.unwrap().text, "bread");
+        assert_eq!(db.purge_trash(Utc::now() - retention).await.unwrap(), 0);
+    }
+}
@@ -1,5 +1,92 @@
 CREATE TABLE IF NOT EXISTS todos(
     id SERIAL PRIMARY KEY NOT NULL,
     text TEXT NOT NULL,
-    completed BOOLEAN NOT NULL DEFAULT FALSE
+    completed BOOLEAN NOT NULL DEFAULT FALSE,
+    version INTEGER NOT NULL DEFAULT 1,
+    owner_id TEXT NOT NULL DEFAULT '',
+    deleted_at TIMESTAMPTZ
 );
+
+-- Databases created before the version column
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS version INTEGER NOT NULL DEFAULT 1;
+
+-- Databases created before the owner_id column, their todos belong to nobody and only admins see them
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS owner_id TEXT NOT NULL DEFAULT '';
+CREATE INDEX IF NOT EXISTS todos_owner_id ON todos(owner_id);
+
+-- Databases created before the trash. Deleted todos go there first, the server purges them after the retention
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS todos_deleted_at ON todos(deleted_at) WHERE deleted_at IS NOT NULL;
+
+-- API keys for automation, only a salted sha256 of the secret part is stored
+CREATE TABLE IF NOT EXISTS api_keys(
+    id SERIAL PRIMARY KEY NOT NULL,
+    prefix TEXT NOT NULL UNIQUE,
+    salt TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    name TEXT NOT NULL,
+    owner_id TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMPTZ
+);
+
+-- Audit log, the server writes an entry in the transaction of every write of a todo.
+-- There is no foreign key, the history of a purged todo is kept.
+CREATE TABLE IF NOT EXISTS todo_events(
+    id SERIAL PRIMARY KEY NOT NULL,
+    todo_id INTEGER NOT NULL,
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    before JSONB,
+    after JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS todo_events_todo_id ON todo_events(todo_id);
+CREATE INDEX IF NOT EXISTS todo_events_actor ON todo_events(actor, id);
+CREATE INDEX IF NOT EXISTS todo_events_created_at ON todo_events(created_at);
+
+-- Tell the listening servers about every change, so their subscribers see the writes of every instance
+CREATE OR REPLACE FUNCTION todos_notify() RETURNS TRIGGER AS $$
+DECLARE
+    todo todos;
+    action TEXT;
+    payload TEXT;
+BEGIN
+    -- The todos in the trash were already announced as deleted when they went there
+    IF TG_OP = 'DELETE' THEN
+        IF OLD.deleted_at IS NOT NULL THEN
+            RETURN NULL;
+        END IF;
+        todo := OLD;
+        action := 'deleted';
+    ELSIF TG_OP = 'UPDATE' AND NEW.deleted_at IS NOT NULL THEN
+        IF OLD.deleted_at IS NOT NULL THEN
+            RETURN NULL;
+        END IF;
+        todo := NEW;
+        action := 'deleted';
+    ELSIF TG_OP = 'UPDATE' AND OLD.deleted_at IS NOT NULL THEN
+        todo := NEW;
+        action := 'created';
+    ELSIF TG_OP = 'UPDATE' THEN
+        todo := NEW;
+        action := 'updated';
+    ELSE
+        todo := NEW;
+        action := 'created';
+    END IF;
+    payload := json_build_object('action', action, 'todo', row_to_json(todo))::TEXT;
+    -- A payload must stay under 8000 bytes, the servers read long todos back from the table
+    IF octet_length(payload) > 7900 THEN
+        todo.text := left(todo.text, 1000);
+        payload := json_build_object('action', action, 'todo', row_to_json(todo), 'truncated', TRUE)::TEXT;
+    END IF;
+    PERFORM pg_notify('todos_changed', payload);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS todos_changed ON todos;
+CREATE TRIGGER todos_changed AFTER INSERT OR UPDATE OR DELETE ON todos
+    FOR EACH ROW EXECUTE PROCEDURE todos_notify();